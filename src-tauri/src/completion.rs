@@ -0,0 +1,81 @@
+use llm::InferenceError;
+use serde::ser::SerializeStruct;
+
+use crate::inference_stats::CompletionStats;
+use crate::inference_worker::{JobError, JobId};
+
+/// Why the model stopped generating.
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    EndOfText,
+    MaxTokens,
+    StopSequence,
+    /// Cancelled by the user while generating, the answer is what was
+    /// generated until then
+    Cancelled,
+}
+
+/// Answer of a chat turn or a question.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Completion {
+    pub request_id: JobId,
+    pub answer: String,
+    pub stop_reason: StopReason,
+    pub stats: CompletionStats,
+}
+
+/// Error of the inference commands, serialized as `{ kind, message }`.
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum CommandError {
+    #[error("No model loaded")]
+    NoModelLoaded,
+    #[error("The conversation doesn't fit in the context window")]
+    ContextFull,
+    /// Cancelled before the model started on the answer
+    #[error("The request was cancelled")]
+    Halted,
+    #[error("Inference failed: {0}")]
+    InferenceFailed(String),
+    #[error("{0}")]
+    InvalidMessage(String),
+}
+
+impl CommandError {
+    fn kind(&self) -> &'static str {
+        match self {
+            CommandError::NoModelLoaded => "no_model_loaded",
+            CommandError::ContextFull => "context_full",
+            CommandError::Halted => "halted",
+            CommandError::InferenceFailed(_) => "inference_failed",
+            CommandError::InvalidMessage(_) => "invalid_message",
+        }
+    }
+}
+
+impl serde::Serialize for CommandError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut error = serializer.serialize_struct("CommandError", 2)?;
+        error.serialize_field("kind", self.kind())?;
+        error.serialize_field("message", &self.to_string())?;
+        error.end()
+    }
+}
+
+impl From<InferenceError> for CommandError {
+    fn from(err: InferenceError) -> Self {
+        match err {
+            InferenceError::ContextFull => CommandError::ContextFull,
+            err => CommandError::InferenceFailed(err.to_string()),
+        }
+    }
+}
+
+impl From<JobError> for CommandError {
+    fn from(err: JobError) -> Self {
+        match err {
+            JobError::Cancelled => CommandError::Halted,
+            JobError::Panicked(message) => CommandError::InferenceFailed(message),
+        }
+    }
+}
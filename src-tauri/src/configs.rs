@@ -1,6 +1,9 @@
 use std::process::Command;
 
 use crate::localstore;
+use crate::parameters::InferenceParameters;
+use crate::presets::Preset;
+use crate::prompt_template::ChatTemplate;
 
 #[derive(serde::Deserialize)]
 struct ConfigModels {
@@ -15,9 +18,20 @@ pub struct ConfigLanguageModel {
     pub url: String,
     pub image: String,
     pub prompt_template: String,
+    #[serde(default)]
+    pub chat_template: Option<ChatTemplate>,
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
+    #[serde(default)]
+    pub parameters: Option<InferenceParameters>,
     pub size: String,
 }
 
+#[derive(serde::Deserialize)]
+struct ConfigPresets {
+    presets: Vec<Preset>,
+}
+
 pub fn get_config_language_models(app_handle: &tauri::AppHandle) -> Vec<ConfigLanguageModel> {
     let resource_path = app_handle
         .path_resolver()
@@ -35,6 +49,22 @@ pub fn get_config_language_models(app_handle: &tauri::AppHandle) -> Vec<ConfigLa
     return config_models.models;
 }
 
+pub fn get_config_presets(app_handle: &tauri::AppHandle) -> Vec<Preset> {
+    let resource_path = app_handle
+        .path_resolver()
+        .resolve_resource("configs/presets.json")
+        .expect("failed to resolve resource");
+
+    let presets_file = std::fs::File::open(&resource_path).expect(&format!(
+        "failed to open file: {} ",
+        resource_path.to_str().unwrap()
+    ));
+
+    let config_presets: ConfigPresets = serde_json::from_reader(presets_file).unwrap();
+
+    return config_presets.presets;
+}
+
 #[tauri::command]
 pub fn show_in_folder(path: String) {
     #[cfg(target_os = "windows")]
@@ -84,14 +114,21 @@ pub fn show_in_folder(path: String) {
 
 #[tauri::command]
 pub async fn choose_directory(app_handle: tauri::AppHandle) -> Result<String, String> {
+    let path = pick_directory()?;
+    localstore::save_models_folder(app_handle, path.to_str().unwrap().to_string()).unwrap();
+    return Ok(path.to_str().unwrap().to_string());
+}
+
+/// Asks for a folder. Blocks until the dialog closes, so only call it from
+/// async commands.
+pub(crate) fn pick_directory() -> Result<std::path::PathBuf, String> {
     use tauri::api::dialog::blocking::FileDialogBuilder;
 
     let dialog_result = FileDialogBuilder::new().pick_folder();
 
     match dialog_result {
         Some(path) => {
-            localstore::save_models_folder(app_handle, path.to_str().unwrap().to_string()).unwrap();
-            return Ok(path.to_str().unwrap().to_string());
+            return Ok(path);
         }
         None => {
             return Err("No path selected".to_string());
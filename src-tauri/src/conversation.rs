@@ -0,0 +1,280 @@
+pub type MessageId = u64;
+pub type ConversationId = i64;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Message {
+    pub(crate) id: MessageId,
+    /// Message this one answers or follows, `None` for the first messages
+    pub(crate) parent: Option<MessageId>,
+    pub(crate) text: String,
+    pub(crate) role: MessageRole,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub enum MessageRole {
+    #[serde(rename = "human")]
+    Human,
+    AI,
+}
+
+/// A message of the selected path, with the alternatives to it.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct PathMessage {
+    #[serde(flatten)]
+    pub message: Message,
+    /// Ids of the messages with the same parent, this one included, oldest
+    /// first
+    pub siblings: Vec<MessageId>,
+}
+
+/// The chat history, a tree of messages. Editing a message or regenerating a
+/// reply adds a sibling instead of replacing it, and the conversation shown
+/// and sent to the model is the path from the first message to the selected
+/// one.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct Conversation {
+    /// Row in the conversation store, `None` until the first answer is saved
+    id: Option<ConversationId>,
+    messages: Vec<Message>,
+    next_id: MessageId,
+    /// Last message of the selected path
+    selected: Option<MessageId>,
+}
+
+impl Conversation {
+    /// Rebuilds a conversation read from the conversation store.
+    pub fn from_stored(
+        id: ConversationId,
+        messages: Vec<Message>,
+        selected: Option<MessageId>,
+    ) -> Self {
+        let next_id = messages
+            .iter()
+            .map(|message| message.id + 1)
+            .max()
+            .unwrap_or(0);
+        return Conversation {
+            id: Some(id),
+            messages,
+            next_id,
+            selected,
+        };
+    }
+
+    pub fn id(&self) -> Option<ConversationId> {
+        return self.id;
+    }
+
+    pub fn set_id(&mut self, id: ConversationId) {
+        self.id = Some(id);
+    }
+
+    /// Every message of every branch.
+    pub fn all_messages(&self) -> &[Message] {
+        return &self.messages;
+    }
+
+    pub fn selected(&self) -> Option<MessageId> {
+        return self.selected;
+    }
+
+    /// Messages from the first one to the selected one.
+    pub fn selected_path(&self) -> Vec<Message> {
+        let mut path = Vec::new();
+        let mut current = self.selected;
+        while let Some(id) = current {
+            match self.find(id) {
+                Some(message) => {
+                    current = message.parent;
+                    path.push(message.clone());
+                }
+                None => break,
+            }
+        }
+        path.reverse();
+        return path;
+    }
+
+    /// The selected path, with the siblings of every message.
+    pub fn selected_path_with_siblings(&self) -> Vec<PathMessage> {
+        return self
+            .selected_path()
+            .into_iter()
+            .map(|message| PathMessage {
+                siblings: self.children(message.parent),
+                message,
+            })
+            .collect();
+    }
+
+    pub fn last(&self) -> Option<&Message> {
+        return self.selected.and_then(|id| self.find(id));
+    }
+
+    /// Adds a message after the selected one and selects it.
+    pub fn push(&mut self, role: MessageRole, text: String) -> MessageId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.messages.push(Message {
+            id,
+            parent: self.selected,
+            text,
+            role,
+        });
+        self.selected = Some(id);
+        return id;
+    }
+
+    /// Appends text to the selected message, used when a reply is continued.
+    pub fn append_to_last(&mut self, text: &str) {
+        let selected = self.selected;
+        if let Some(message) = self
+            .messages
+            .iter_mut()
+            .find(|message| Some(message.id) == selected)
+        {
+            message.text.push_str(text);
+        }
+    }
+
+    pub fn find(&self, id: MessageId) -> Option<&Message> {
+        return self.messages.iter().find(|message| message.id == id);
+    }
+
+    /// Selects the parent of a message, so the next message pushed becomes
+    /// an alternative to it. The message and its replies are kept.
+    pub fn branch_from(&mut self, id: MessageId) {
+        if let Some(message) = self.find(id) {
+            self.selected = message.parent;
+        }
+    }
+
+    /// Selects the path through a message. After it, the path follows the
+    /// newest reply of every message.
+    pub fn select(&mut self, id: MessageId) -> bool {
+        if self.find(id).is_none() {
+            return false;
+        }
+        let mut selected = id;
+        while let Some(newest_child) = self.children(Some(selected)).last().copied() {
+            selected = newest_child;
+        }
+        self.selected = Some(selected);
+        return true;
+    }
+
+    fn children(&self, parent: Option<MessageId>) -> Vec<MessageId> {
+        return self
+            .messages
+            .iter()
+            .filter(|message| message.parent == parent)
+            .map(|message| message.id)
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path_ids(conversation: &Conversation) -> Vec<MessageId> {
+        return conversation
+            .selected_path()
+            .iter()
+            .map(|message| message.id)
+            .collect();
+    }
+
+    /// A question, an answer and a follow-up with its answer.
+    fn exchange() -> Conversation {
+        let mut conversation = Conversation::default();
+        conversation.push(MessageRole::Human, "Question".to_string());
+        conversation.push(MessageRole::AI, "Answer".to_string());
+        conversation.push(MessageRole::Human, "Follow-up".to_string());
+        conversation.push(MessageRole::AI, "Second answer".to_string());
+        return conversation;
+    }
+
+    #[test]
+    fn editing_a_message_adds_a_sibling() {
+        let mut conversation = exchange();
+        conversation.branch_from(2);
+        let edited = conversation.push(MessageRole::Human, "Edited follow-up".to_string());
+
+        assert_eq!(edited, 4);
+        assert_eq!(path_ids(&conversation), vec![0, 1, 4]);
+        // The original and its answer are kept
+        assert_eq!(conversation.all_messages().len(), 5);
+        let path = conversation.selected_path_with_siblings();
+        assert_eq!(path[0].siblings, vec![0]);
+        assert_eq!(path[1].siblings, vec![1]);
+        assert_eq!(path[2].siblings, vec![2, 4]);
+        assert_eq!(path[2].message.text, "Edited follow-up");
+    }
+
+    #[test]
+    fn regenerating_a_reply_adds_a_sibling() {
+        let mut conversation = exchange();
+        conversation.branch_from(3);
+        assert_eq!(conversation.last().map(|message| message.id), Some(2));
+        conversation.push(MessageRole::AI, "Another answer".to_string());
+
+        assert_eq!(path_ids(&conversation), vec![0, 1, 2, 4]);
+        let path = conversation.selected_path_with_siblings();
+        assert_eq!(path[3].siblings, vec![3, 4]);
+
+        // Editing the first message starts a new root
+        conversation.branch_from(0);
+        assert_eq!(conversation.selected(), None);
+        conversation.push(MessageRole::Human, "New question".to_string());
+        assert_eq!(path_ids(&conversation), vec![5]);
+        assert_eq!(
+            conversation.selected_path_with_siblings()[0].siblings,
+            vec![0, 5]
+        );
+    }
+
+    #[test]
+    fn selecting_follows_the_newest_replies() {
+        let mut conversation = exchange();
+        conversation.branch_from(2);
+        conversation.push(MessageRole::Human, "Edited follow-up".to_string());
+        conversation.push(MessageRole::AI, "Answer to the edit".to_string());
+
+        assert!(conversation.select(2));
+        assert_eq!(path_ids(&conversation), vec![0, 1, 2, 3]);
+        assert!(conversation.select(0));
+        assert_eq!(path_ids(&conversation), vec![0, 1, 4, 5]);
+        assert!(conversation.select(3));
+        assert_eq!(conversation.selected(), Some(3));
+
+        assert!(!conversation.select(42));
+        assert_eq!(conversation.selected(), Some(3));
+    }
+
+    #[test]
+    fn appends_to_the_selected_message() {
+        let mut conversation = exchange();
+        conversation.branch_from(2);
+        conversation.append_to_last(" continued");
+
+        assert_eq!(conversation.find(1).unwrap().text, "Answer continued");
+        assert_eq!(conversation.find(3).unwrap().text, "Second answer");
+
+        let mut empty = Conversation::default();
+        empty.append_to_last("nothing");
+        assert!(empty.all_messages().is_empty());
+    }
+
+    #[test]
+    fn continues_ids_after_stored_messages() {
+        let stored = exchange();
+        let mut conversation =
+            Conversation::from_stored(7, stored.all_messages().to_vec(), Some(1));
+
+        assert_eq!(conversation.id(), Some(7));
+        assert_eq!(path_ids(&conversation), vec![0, 1]);
+        assert_eq!(conversation.push(MessageRole::Human, "Next".to_string()), 4);
+        assert_eq!(conversation.find(4).unwrap().parent, Some(1));
+    }
+}
@@ -0,0 +1,983 @@
+use std::fs;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use tauri::Manager;
+
+use crate::conversation::{
+    Conversation, ConversationId, Message, MessageId, MessageRole, PathMessage,
+};
+use crate::inference_stats::CompletionStats;
+use crate::inference_worker::InferenceWorker;
+use crate::language_model::{self, ChatState};
+use crate::{localstore, profiles};
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Database(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Serialization(#[from] serde_json::Error),
+    #[error("Conversation {0} not found")]
+    NotFound(ConversationId),
+}
+
+const DATABASE_FILENAME: &str = "conversations.db";
+
+/// Longest title taken from the first message of a conversation
+const TITLE_LENGTH: usize = 50;
+
+/// Schema changes, applied in order. `PRAGMA user_version` holds how many
+/// ran already.
+const MIGRATIONS: [&str; 4] = [
+    "
+    CREATE TABLE IF NOT EXISTS conversations (
+        id INTEGER PRIMARY KEY,
+        title TEXT NOT NULL,
+        model_filename TEXT NOT NULL,
+        parameters TEXT NOT NULL,
+        selected_message INTEGER,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS messages (
+        row_id INTEGER PRIMARY KEY,
+        conversation_id INTEGER NOT NULL REFERENCES conversations(id),
+        id INTEGER NOT NULL,
+        parent INTEGER,
+        role TEXT NOT NULL,
+        text TEXT NOT NULL,
+        UNIQUE (conversation_id, id)
+    );
+    ",
+    // Full-text index of the messages, kept up to date by triggers. It points
+    // at `row_id`, the implicit rowid could change on VACUUM.
+    "
+    ALTER TABLE messages ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+    UPDATE messages SET created_at =
+        (SELECT created_at FROM conversations WHERE conversations.id = messages.conversation_id);
+    CREATE VIRTUAL TABLE messages_fts USING fts5(text, content='messages', content_rowid='row_id');
+    CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+        INSERT INTO messages_fts(rowid, text) VALUES (new.row_id, new.text);
+    END;
+    CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+        INSERT INTO messages_fts(messages_fts, rowid, text) VALUES ('delete', old.row_id, old.text);
+    END;
+    CREATE TRIGGER messages_fts_update AFTER UPDATE OF text ON messages BEGIN
+        INSERT INTO messages_fts(messages_fts, rowid, text) VALUES ('delete', old.row_id, old.text);
+        INSERT INTO messages_fts(rowid, text) VALUES (new.row_id, new.text);
+    END;
+    INSERT INTO messages_fts(messages_fts) VALUES ('rebuild');
+    ",
+    // Set while the title is the first line of the first message, until
+    // the model writes one or the user renames the conversation
+    "
+    ALTER TABLE conversations ADD COLUMN needs_title INTEGER NOT NULL DEFAULT 0;
+    ",
+    // Token counts and timings of the recent completions of each model
+    "
+    CREATE TABLE inference_stats (
+        id INTEGER PRIMARY KEY,
+        model_filename TEXT NOT NULL,
+        prompt_tokens INTEGER NOT NULL,
+        generated_tokens INTEGER NOT NULL,
+        feed_prompt_ms INTEGER NOT NULL,
+        predict_ms INTEGER NOT NULL,
+        tokens_per_second REAL NOT NULL,
+        finished_at INTEGER NOT NULL
+    );
+    CREATE INDEX inference_stats_model ON inference_stats(model_filename, id);
+    ",
+];
+
+/// Marks the matches in search snippets, characters that don't show up in
+/// chat text
+const HIGHLIGHT_START: &str = "\u{2}";
+const HIGHLIGHT_END: &str = "\u{3}";
+
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+
+/// Most words around the matches in a search snippet
+const SNIPPET_WORDS: usize = 16;
+
+#[derive(serde::Serialize, Debug)]
+pub struct ConversationSummary {
+    pub id: ConversationId,
+    pub title: String,
+    /// Model of the last answer
+    pub model_filename: String,
+    /// Seconds since the Unix epoch
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// A conversation with everything the store keeps about it.
+#[derive(serde::Serialize, Debug)]
+pub struct StoredConversation {
+    #[serde(flatten)]
+    pub summary: ConversationSummary,
+    /// Parameters of the last answer
+    pub parameters: serde_json::Value,
+    pub selected_message: Option<MessageId>,
+    /// Every message of every branch
+    pub messages: Vec<StoredMessage>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct StoredMessage {
+    #[serde(flatten)]
+    pub message: Message,
+    /// Seconds since the Unix epoch
+    pub created_at: i64,
+}
+
+impl StoredConversation {
+    pub fn to_conversation(&self) -> Conversation {
+        return Conversation::from_stored(
+            self.summary.id,
+            self.messages
+                .iter()
+                .map(|stored| stored.message.clone())
+                .collect(),
+            self.selected_message,
+        );
+    }
+
+    /// Messages of the selected branch, from the first one.
+    pub fn selected_path(&self) -> Vec<&StoredMessage> {
+        return self
+            .to_conversation()
+            .selected_path()
+            .iter()
+            .filter_map(|message| {
+                self.messages
+                    .iter()
+                    .find(|stored| stored.message.id == message.id)
+            })
+            .collect();
+    }
+}
+
+/// A conversation to add to the store as it is, from an importer.
+#[derive(Debug)]
+pub struct NewConversation {
+    pub title: String,
+    /// Model that wrote the answers, as the source names it
+    pub model_filename: String,
+    /// Seconds since the Unix epoch
+    pub created_at: i64,
+    /// Every message of every branch, parents before their replies
+    pub messages: Vec<StoredMessage>,
+    pub selected_message: Option<MessageId>,
+}
+
+/// A message found by [`search_conversations`].
+#[derive(serde::Serialize, Debug)]
+pub struct SearchResult {
+    pub conversation_id: ConversationId,
+    pub conversation_title: String,
+    pub message_id: MessageId,
+    pub role: MessageRole,
+    /// Text around the matches
+    pub snippet: Vec<SnippetPart>,
+    /// Seconds since the Unix epoch
+    pub created_at: i64,
+}
+
+#[derive(serde::Serialize, Debug, PartialEq)]
+pub struct SnippetPart {
+    pub text: String,
+    /// Part of the text matched the query
+    pub highlighted: bool,
+}
+
+/// Saved conversations and inference stats, in a SQLite database next to
+/// `store.bin`. The chat writes every answer through to it.
+pub struct ConversationStore {
+    connection: Mutex<Connection>,
+}
+
+impl ConversationStore {
+    pub fn open(app_handle: &tauri::AppHandle) -> Result<Self> {
+        let data_dir = app_handle.path_resolver().app_data_dir().unwrap();
+        fs::create_dir_all(&data_dir)?;
+        let mut connection = Connection::open(data_dir.join(DATABASE_FILENAME))?;
+        migrate(&mut connection)?;
+        Ok(ConversationStore {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Saves the messages and the selected branch, adding the conversation
+    /// if it isn't stored yet.
+    fn save(
+        &self,
+        conversation: &mut Conversation,
+        model_filename: &str,
+        parameters: &str,
+    ) -> Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let now = now();
+        let selected = conversation.selected().map(|id| id as i64);
+
+        let updated = match conversation.id() {
+            Some(id) => transaction.execute(
+                "UPDATE conversations
+                 SET model_filename = ?1, parameters = ?2, selected_message = ?3, updated_at = ?4
+                 WHERE id = ?5",
+                params![model_filename, parameters, selected, now, id],
+            )?,
+            None => 0,
+        };
+        let id = match conversation.id() {
+            Some(id) if updated > 0 => id,
+            // New, or deleted while it was open
+            _ => {
+                transaction.execute(
+                    "INSERT INTO conversations (title, model_filename, parameters,
+                         selected_message, created_at, updated_at, needs_title)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?5, 1)",
+                    params![
+                        default_title(conversation),
+                        model_filename,
+                        parameters,
+                        selected,
+                        now
+                    ],
+                )?;
+                transaction.last_insert_rowid()
+            }
+        };
+
+        for message in conversation.all_messages() {
+            // Only a continued reply changes, so the others aren't reindexed
+            transaction.execute(
+                "INSERT INTO messages (conversation_id, id, parent, role, text, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                 ON CONFLICT (conversation_id, id) DO UPDATE SET text = excluded.text
+                 WHERE text != excluded.text",
+                params![
+                    id,
+                    message.id as i64,
+                    message.parent.map(|parent| parent as i64),
+                    role_name(&message.role),
+                    message.text,
+                    now,
+                ],
+            )?;
+        }
+        transaction.commit()?;
+        conversation.set_id(id);
+        Ok(())
+    }
+
+    /// Adds the conversations all at once, none of them if one fails.
+    pub(crate) fn insert_all(&self, conversations: &[NewConversation]) -> Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        for conversation in conversations {
+            insert(&transaction, conversation)?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Conversations, the most recently updated first.
+    pub(crate) fn list(&self) -> Result<Vec<ConversationSummary>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT id, title, model_filename, created_at, updated_at
+             FROM conversations ORDER BY updated_at DESC",
+        )?;
+        let summaries = statement
+            .query_map([], |row| {
+                Ok(ConversationSummary {
+                    id: row.get(0)?,
+                    title: row.get(1)?,
+                    model_filename: row.get(2)?,
+                    created_at: row.get(3)?,
+                    updated_at: row.get(4)?,
+                })
+            })?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(summaries)
+    }
+
+    fn load(&self, id: ConversationId) -> Result<Conversation> {
+        return Ok(self.load_stored(id)?.to_conversation());
+    }
+
+    /// The conversation with everything stored about it.
+    pub(crate) fn load_stored(&self, id: ConversationId) -> Result<StoredConversation> {
+        let connection = self.connection.lock().unwrap();
+        let (summary, parameters, selected) = connection
+            .query_row(
+                "SELECT title, model_filename, created_at, updated_at, parameters, selected_message
+                 FROM conversations WHERE id = ?1",
+                [id],
+                |row| {
+                    let summary = ConversationSummary {
+                        id,
+                        title: row.get(0)?,
+                        model_filename: row.get(1)?,
+                        created_at: row.get(2)?,
+                        updated_at: row.get(3)?,
+                    };
+                    let parameters: String = row.get(4)?;
+                    let selected: Option<i64> = row.get(5)?;
+                    Ok((summary, parameters, selected))
+                },
+            )
+            .optional()?
+            .ok_or(Error::NotFound(id))?;
+
+        let mut statement = connection.prepare(
+            "SELECT id, parent, role, text, created_at FROM messages
+             WHERE conversation_id = ?1 ORDER BY id",
+        )?;
+        let messages = statement
+            .query_map([id], |row| {
+                let message_id: i64 = row.get(0)?;
+                let parent: Option<i64> = row.get(1)?;
+                let role: String = row.get(2)?;
+                Ok(StoredMessage {
+                    message: Message {
+                        id: message_id as u64,
+                        parent: parent.map(|parent| parent as u64),
+                        text: row.get(3)?,
+                        role: parse_role(&role),
+                    },
+                    created_at: row.get(4)?,
+                })
+            })?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(StoredConversation {
+            summary,
+            parameters: serde_json::from_str(&parameters)?,
+            selected_message: selected.map(|selected| selected as u64),
+            messages,
+        })
+    }
+
+    /// Messages matching the words of the query, the most relevant first.
+    /// The last word also matches as a prefix, so results show up while
+    /// typing.
+    fn search(&self, query: &str, limit: usize) -> Result<Vec<SearchResult>> {
+        let match_query = match fts_query(query) {
+            Some(match_query) => match_query,
+            None => return Ok(Vec::new()),
+        };
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT messages.conversation_id, conversations.title, messages.id, messages.role,
+                    snippet(messages_fts, 0, ?2, ?3, '…', ?4), messages.created_at
+             FROM messages_fts
+             JOIN messages ON messages.row_id = messages_fts.rowid
+             JOIN conversations ON conversations.id = messages.conversation_id
+             WHERE messages_fts MATCH ?1
+             ORDER BY bm25(messages_fts)
+             LIMIT ?5",
+        )?;
+        let results = statement
+            .query_map(
+                params![
+                    match_query,
+                    HIGHLIGHT_START,
+                    HIGHLIGHT_END,
+                    SNIPPET_WORDS as i64,
+                    limit as i64
+                ],
+                |row| {
+                    let message_id: i64 = row.get(2)?;
+                    let role: String = row.get(3)?;
+                    let snippet: String = row.get(4)?;
+                    Ok(SearchResult {
+                        conversation_id: row.get(0)?,
+                        conversation_title: row.get(1)?,
+                        message_id: message_id as u64,
+                        role: parse_role(&role),
+                        snippet: snippet_parts(&snippet),
+                        created_at: row.get(5)?,
+                    })
+                },
+            )?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(results)
+    }
+
+    fn rename(&self, id: ConversationId, title: &str) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        let updated = connection.execute(
+            "UPDATE conversations SET title = ?1, needs_title = 0 WHERE id = ?2",
+            params![title, id],
+        )?;
+        if updated == 0 {
+            return Err(Error::NotFound(id));
+        }
+        Ok(())
+    }
+
+    /// Whether the conversation still has the title taken from its first
+    /// message.
+    pub(crate) fn needs_title(&self, id: ConversationId) -> Result<bool> {
+        let connection = self.connection.lock().unwrap();
+        let needs_title: Option<bool> = connection
+            .query_row(
+                "SELECT needs_title FROM conversations WHERE id = ?1",
+                [id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(needs_title.unwrap_or(false))
+    }
+
+    /// Sets a title written by the model, unless the user renamed the
+    /// conversation in the meantime. Returns whether it was set.
+    pub(crate) fn set_generated_title(&self, id: ConversationId, title: &str) -> Result<bool> {
+        let connection = self.connection.lock().unwrap();
+        let updated = connection.execute(
+            "UPDATE conversations SET title = ?1, needs_title = 0
+             WHERE id = ?2 AND needs_title = 1",
+            params![title, id],
+        )?;
+        Ok(updated > 0)
+    }
+
+    fn delete(&self, id: ConversationId) -> Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM messages WHERE conversation_id = ?1", [id])?;
+        transaction.execute("DELETE FROM conversations WHERE id = ?1", [id])?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Adds the stats of a completion, keeping only the latest `keep` of the
+    /// model.
+    pub(crate) fn add_inference_stats(
+        &self,
+        model_filename: &str,
+        stats: &CompletionStats,
+        keep: usize,
+    ) -> Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO inference_stats (model_filename, prompt_tokens, generated_tokens,
+                 feed_prompt_ms, predict_ms, tokens_per_second, finished_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                model_filename,
+                stats.prompt_tokens as i64,
+                stats.generated_tokens as i64,
+                stats.feed_prompt_ms as i64,
+                stats.predict_ms as i64,
+                stats.tokens_per_second,
+                stats.finished_at as i64,
+            ],
+        )?;
+        transaction.execute(
+            "DELETE FROM inference_stats WHERE model_filename = ?1 AND id NOT IN
+                 (SELECT id FROM inference_stats WHERE model_filename = ?1
+                  ORDER BY id DESC LIMIT ?2)",
+            params![model_filename, keep as i64],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Stats of the recent completions of a model, oldest first.
+    pub(crate) fn inference_stats(&self, model_filename: &str) -> Result<Vec<CompletionStats>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT prompt_tokens, generated_tokens, feed_prompt_ms, predict_ms,
+                 tokens_per_second, finished_at
+             FROM inference_stats WHERE model_filename = ?1 ORDER BY id",
+        )?;
+        let stats = statement
+            .query_map([model_filename], |row| {
+                Ok(CompletionStats {
+                    prompt_tokens: row.get::<_, i64>(0)? as usize,
+                    generated_tokens: row.get::<_, i64>(1)? as usize,
+                    feed_prompt_ms: row.get::<_, i64>(2)? as u64,
+                    predict_ms: row.get::<_, i64>(3)? as u64,
+                    tokens_per_second: row.get(4)?,
+                    finished_at: row.get::<_, i64>(5)? as u64,
+                })
+            })?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(stats)
+    }
+}
+
+/// Writes the conversation through to the store, with the model and the
+/// parameters in use. Errors are logged, the chat goes on without saving.
+pub fn save(app_handle: &tauri::AppHandle, conversation: &mut Conversation) {
+    let store = app_handle.state::<ConversationStore>();
+    let model_filename = localstore::get_current_model_filename(app_handle.clone());
+    let parameters = profiles::get_effective_profile(app_handle).parameters.value;
+    let result = serde_json::to_string(&parameters)
+        .map_err(Error::from)
+        .and_then(|parameters| store.save(conversation, &model_filename, &parameters));
+    if let Err(err) = result {
+        println!("Error saving conversation: {}", err);
+    }
+}
+
+#[tauri::command]
+pub fn list_conversations(
+    app_handle: tauri::AppHandle,
+) -> std::result::Result<Vec<ConversationSummary>, String> {
+    println!("Command: list_conversations");
+    let store = app_handle.state::<ConversationStore>();
+    return store.list().map_err(|err| err.to_string());
+}
+
+/// Makes a saved conversation the current chat and returns its history.
+/// Queued behind the requests already sent, like `new_chat`.
+#[tauri::command]
+pub async fn open_conversation(
+    conversation_id: ConversationId,
+    app_handle: tauri::AppHandle,
+) -> std::result::Result<Vec<PathMessage>, String> {
+    println!("Command: open_conversation, id:{}", conversation_id);
+    let conversation = app_handle
+        .state::<ConversationStore>()
+        .load(conversation_id)
+        .map_err(|err| err.to_string())?;
+    let history = conversation.selected_path_with_siblings();
+
+    let worker = app_handle.state::<InferenceWorker>();
+    let job_app_handle = app_handle.clone();
+    worker
+        .submit(move |_, _| {
+            language_model::replace_chat(&job_app_handle.state::<ChatState>(), conversation)
+        })
+        .wait()
+        .await
+        .map_err(|err| err.to_string())?;
+    return Ok(history);
+}
+
+/// Searches the messages of every saved conversation.
+#[tauri::command]
+pub fn search_conversations(
+    query: String,
+    limit: Option<usize>,
+    app_handle: tauri::AppHandle,
+) -> std::result::Result<Vec<SearchResult>, String> {
+    println!("Command: search_conversations, query:{}", query);
+    let store = app_handle.state::<ConversationStore>();
+    return store
+        .search(&query, limit.unwrap_or(DEFAULT_SEARCH_LIMIT))
+        .map_err(|err| err.to_string());
+}
+
+#[tauri::command]
+pub fn rename_conversation(
+    conversation_id: ConversationId,
+    title: String,
+    app_handle: tauri::AppHandle,
+) -> std::result::Result<(), String> {
+    println!("Command: rename_conversation, id:{}", conversation_id);
+    if title.trim().is_empty() {
+        return Err("The conversation needs a title".to_string());
+    }
+    let store = app_handle.state::<ConversationStore>();
+    return store
+        .rename(conversation_id, title.trim())
+        .map_err(|err| err.to_string());
+}
+
+/// Deletes a saved conversation. If it is the current chat, a new chat is
+/// started.
+#[tauri::command]
+pub async fn delete_conversation(
+    conversation_id: ConversationId,
+    app_handle: tauri::AppHandle,
+) -> std::result::Result<(), String> {
+    println!("Command: delete_conversation, id:{}", conversation_id);
+    app_handle
+        .state::<ConversationStore>()
+        .delete(conversation_id)
+        .map_err(|err| err.to_string())?;
+
+    let worker = app_handle.state::<InferenceWorker>();
+    let job_app_handle = app_handle.clone();
+    worker
+        .submit(move |_, _| {
+            let chat_state = job_app_handle.state::<ChatState>();
+            let is_open = chat_state.conversation.lock().unwrap().id() == Some(conversation_id);
+            if is_open {
+                language_model::replace_chat(&chat_state, Conversation::default());
+            }
+        })
+        .wait()
+        .await
+        .map_err(|err| err.to_string())?;
+    return Ok(());
+}
+
+/// Adds a conversation and its messages as part of `transaction`.
+fn insert(transaction: &Transaction, conversation: &NewConversation) -> Result<()> {
+    let updated_at = conversation
+        .messages
+        .iter()
+        .map(|stored| stored.created_at)
+        .max()
+        .unwrap_or(conversation.created_at);
+    transaction.execute(
+        "INSERT INTO conversations
+         (title, model_filename, parameters, selected_message, created_at, updated_at)
+         VALUES (?1, ?2, 'null', ?3, ?4, ?5)",
+        params![
+            conversation.title,
+            conversation.model_filename,
+            conversation.selected_message.map(|id| id as i64),
+            conversation.created_at,
+            updated_at
+        ],
+    )?;
+    let id = transaction.last_insert_rowid();
+    for stored in &conversation.messages {
+        transaction.execute(
+            "INSERT INTO messages (conversation_id, id, parent, role, text, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                id,
+                stored.message.id as i64,
+                stored.message.parent.map(|parent| parent as i64),
+                role_name(&stored.message.role),
+                stored.message.text,
+                stored.created_at,
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+fn migrate(connection: &mut Connection) -> Result<()> {
+    let version: i64 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", (index + 1) as i64)?;
+        transaction.commit()?;
+    }
+    Ok(())
+}
+
+/// Turns the words of a search into an FTS5 query, quoted so that characters
+/// like `-` or `:` are searched for instead of read as operators.
+fn fts_query(query: &str) -> Option<String> {
+    let terms: Vec<String> = query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect();
+    if terms.is_empty() {
+        return None;
+    }
+    return Some(format!("{}*", terms.join(" ")));
+}
+
+/// Splits a snippet at the highlight markers.
+fn snippet_parts(snippet: &str) -> Vec<SnippetPart> {
+    let mut parts = Vec::new();
+    for (index, outside) in snippet.split(HIGHLIGHT_START).enumerate() {
+        // Every piece but the first starts with a match
+        let (highlighted, rest) = match outside.split_once(HIGHLIGHT_END) {
+            Some((highlighted, rest)) if index > 0 => (highlighted, rest),
+            _ => ("", outside),
+        };
+        if !highlighted.is_empty() {
+            parts.push(SnippetPart {
+                text: highlighted.to_string(),
+                highlighted: true,
+            });
+        }
+        if !rest.is_empty() {
+            parts.push(SnippetPart {
+                text: rest.to_string(),
+                highlighted: false,
+            });
+        }
+    }
+    return parts;
+}
+
+/// First line of the first message, shortened.
+fn default_title(conversation: &Conversation) -> String {
+    let first_line = conversation
+        .all_messages()
+        .first()
+        .and_then(|message| message.text.lines().find(|line| !line.trim().is_empty()))
+        .unwrap_or("New conversation")
+        .trim();
+    let mut title: String = first_line.chars().take(TITLE_LENGTH).collect();
+    if first_line.chars().count() > TITLE_LENGTH {
+        title.push('…');
+    }
+    return title;
+}
+
+fn role_name(role: &MessageRole) -> &'static str {
+    match role {
+        MessageRole::Human => "human",
+        MessageRole::AI => "AI",
+    }
+}
+
+fn parse_role(role: &str) -> MessageRole {
+    match role {
+        "human" => MessageRole::Human,
+        _ => MessageRole::AI,
+    }
+}
+
+pub(crate) fn now() -> i64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_store() -> ConversationStore {
+        let mut connection = Connection::open_in_memory().unwrap();
+        migrate(&mut connection).unwrap();
+        return ConversationStore {
+            connection: Mutex::new(connection),
+        };
+    }
+
+    /// Saves a conversation of the given messages, one after the other.
+    fn save_messages(store: &ConversationStore, texts: &[&str]) -> ConversationId {
+        let mut conversation = Conversation::default();
+        for (index, text) in texts.iter().enumerate() {
+            let role = if index % 2 == 0 {
+                MessageRole::Human
+            } else {
+                MessageRole::AI
+            };
+            conversation.push(role, text.to_string());
+        }
+        store.save(&mut conversation, "model.bin", "{}").unwrap();
+        return conversation.id().unwrap();
+    }
+
+    fn highlighted(result: &SearchResult) -> Vec<&str> {
+        return result
+            .snippet
+            .iter()
+            .filter(|part| part.highlighted)
+            .map(|part| part.text.as_str())
+            .collect();
+    }
+
+    #[test]
+    fn quotes_the_words_of_a_query() {
+        assert_eq!(fts_query("  "), None);
+        assert_eq!(
+            fts_query("borrow  check"),
+            Some("\"borrow\" \"check\"*".to_string())
+        );
+        assert_eq!(
+            fts_query("say \"hi\" -x:y"),
+            Some("\"say\" \"\"\"hi\"\"\" \"-x:y\"*".to_string())
+        );
+    }
+
+    #[test]
+    fn splits_snippets_at_the_highlight_markers() {
+        let part = |text: &str, highlighted| SnippetPart {
+            text: text.to_string(),
+            highlighted,
+        };
+        assert_eq!(
+            snippet_parts("a \u{2}match\u{3} b \u{2}other\u{3}"),
+            vec![
+                part("a ", false),
+                part("match", true),
+                part(" b ", false),
+                part("other", true),
+            ]
+        );
+        assert_eq!(snippet_parts("\u{2}start\u{3}"), vec![part("start", true)]);
+        assert_eq!(snippet_parts("plain"), vec![part("plain", false)]);
+        assert_eq!(snippet_parts(""), vec![]);
+    }
+
+    #[test]
+    fn searches_with_the_last_word_as_a_prefix() {
+        let store = open_store();
+        let id = save_messages(&store, &["What is borrowing?", "A reference to a value"]);
+
+        let results = store.search("borrow", 10).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].conversation_id, id);
+        assert_eq!(results[0].message_id, 0);
+        assert_eq!(results[0].role, MessageRole::Human);
+        assert_eq!(highlighted(&results[0]), vec!["borrowing"]);
+
+        // Only the last word is a prefix
+        assert_eq!(store.search("refer a", 10).unwrap().len(), 0);
+        assert_eq!(store.search("a refer", 10).unwrap().len(), 1);
+        assert!(store.search(" ", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn searches_for_operator_characters() {
+        let store = open_store();
+        save_messages(
+            &store,
+            &["Is C++ or \"Rust\" faster?", "key:value and -flag"],
+        );
+
+        assert_eq!(store.search("c++", 10).unwrap().len(), 1);
+        assert_eq!(store.search("\"rust", 10).unwrap().len(), 1);
+        assert_eq!(store.search("key:value -flag", 10).unwrap().len(), 1);
+        assert_eq!(store.search("NOT AND", 10).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn ranks_the_most_relevant_messages_first() {
+        let store = open_store();
+        save_messages(
+            &store,
+            &[
+                "Some words about many things, and lifetimes once",
+                "Unrelated",
+            ],
+        );
+        let relevant = save_messages(&store, &["lifetimes, lifetimes and lifetimes"]);
+
+        let results = store.search("lifetimes", 10).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].conversation_id, relevant);
+        assert_eq!(store.search("lifetimes", 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn saves_and_loads_every_branch() {
+        let store = open_store();
+        let mut conversation = Conversation::default();
+        conversation.push(MessageRole::Human, "Question".to_string());
+        conversation.push(MessageRole::AI, "First answer".to_string());
+        conversation.branch_from(1);
+        conversation.push(MessageRole::AI, "Second answer".to_string());
+        store
+            .save(&mut conversation, "model.bin", "{\"temperature\":0.5}")
+            .unwrap();
+        let id = conversation.id().unwrap();
+
+        let stored = store.load_stored(id).unwrap();
+        assert_eq!(stored.summary.title, "Question");
+        assert_eq!(stored.summary.model_filename, "model.bin");
+        assert_eq!(stored.parameters["temperature"], 0.5);
+        assert_eq!(stored.selected_message, Some(2));
+        let messages: Vec<(MessageId, Option<MessageId>, &str)> = stored
+            .messages
+            .iter()
+            .map(|stored| {
+                let message = &stored.message;
+                (message.id, message.parent, message.text.as_str())
+            })
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (0, None, "Question"),
+                (1, Some(0), "First answer"),
+                (2, Some(0), "Second answer"),
+            ]
+        );
+
+        let mut loaded = store.load(id).unwrap();
+        let path = loaded.selected_path_with_siblings();
+        assert_eq!(path.len(), 2);
+        assert_eq!(path[1].siblings, vec![1, 2]);
+        // Ids go on after the stored ones
+        assert_eq!(loaded.push(MessageRole::Human, "More".to_string()), 3);
+
+        conversation.select(1);
+        store.save(&mut conversation, "model.bin", "{}").unwrap();
+        assert_eq!(store.load_stored(id).unwrap().selected_message, Some(1));
+        assert_eq!(store.list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn updates_a_continued_reply() {
+        let store = open_store();
+        let mut conversation = Conversation::default();
+        conversation.push(MessageRole::Human, "Tell a story".to_string());
+        conversation.push(MessageRole::AI, "Once upon".to_string());
+        store.save(&mut conversation, "model.bin", "{}").unwrap();
+        let id = conversation.id().unwrap();
+
+        conversation.append_to_last(" a time");
+        store.save(&mut conversation, "model.bin", "{}").unwrap();
+
+        let stored = store.load_stored(id).unwrap();
+        assert_eq!(stored.messages.len(), 2);
+        assert_eq!(stored.messages[1].message.text, "Once upon a time");
+        // The search index has the new text
+        assert_eq!(store.search("time", 10).unwrap().len(), 1);
+        assert_eq!(store.search("upon", 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn deletes_conversations_and_their_messages() {
+        let store = open_store();
+        let mut conversation = Conversation::default();
+        conversation.push(MessageRole::Human, "Forget this".to_string());
+        store.save(&mut conversation, "model.bin", "{}").unwrap();
+        let id = conversation.id().unwrap();
+
+        store.delete(id).unwrap();
+        assert!(matches!(store.load_stored(id), Err(Error::NotFound(_))));
+        assert!(store.list().unwrap().is_empty());
+        assert!(store.search("forget", 10).unwrap().is_empty());
+        let message_count: i64 = store
+            .connection
+            .lock()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM messages", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(message_count, 0);
+
+        // Saving it while it's still open adds it again
+        store.save(&mut conversation, "model.bin", "{}").unwrap();
+        assert_eq!(store.list().unwrap().len(), 1);
+        let stored = store.load_stored(conversation.id().unwrap()).unwrap();
+        assert_eq!(stored.messages[0].message.text, "Forget this");
+    }
+
+    #[test]
+    fn renaming_keeps_the_title_from_the_model() {
+        let store = open_store();
+        let id = save_messages(&store, &["Question", "Answer"]);
+        assert!(store.needs_title(id).unwrap());
+
+        store.rename(id, "Mine").unwrap();
+        assert!(!store.needs_title(id).unwrap());
+        assert!(!store.set_generated_title(id, "Generated").unwrap());
+        assert_eq!(store.load_stored(id).unwrap().summary.title, "Mine");
+
+        let other = save_messages(&store, &["Another question"]);
+        assert!(store.set_generated_title(other, "Generated").unwrap());
+        assert!(!store.needs_title(other).unwrap());
+        assert!(matches!(
+            store.rename(42, "Missing"),
+            Err(Error::NotFound(42))
+        ));
+    }
+}
@@ -0,0 +1,348 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tauri::Manager;
+
+use crate::conversation::{ConversationId, MessageRole};
+use crate::conversation_store::{self, ConversationStore, StoredConversation};
+use crate::{configs, profiles};
+
+/// Written in the `format` field of JSON exports
+const JSON_FORMAT: &str = "secondbrain-conversation";
+const JSON_VERSION: u32 = 1;
+
+#[derive(serde::Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    Markdown,
+    Json,
+    Html,
+}
+
+impl ExportFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Json => "json",
+            ExportFormat::Html => "html",
+        }
+    }
+}
+
+/// A conversation as written to JSON, every branch included.
+#[derive(serde::Serialize)]
+struct JsonExport<'a> {
+    format: &'static str,
+    version: u32,
+    /// Seconds since the Unix epoch
+    exported_at: i64,
+    model: ModelMetadata,
+    #[serde(flatten)]
+    conversation: &'a StoredConversation,
+}
+
+/// What the model catalog knows about the model of a conversation.
+#[derive(serde::Serialize)]
+struct ModelMetadata {
+    filename: String,
+    name: Option<String>,
+    arquitecture: Option<String>,
+    url: Option<String>,
+}
+
+/// Writes a saved conversation to a folder the user picks and returns the
+/// path of the file.
+#[tauri::command]
+pub async fn export_conversation(
+    conversation_id: ConversationId,
+    format: ExportFormat,
+    app_handle: tauri::AppHandle,
+) -> Result<String, String> {
+    println!("Command: export_conversation, id:{}", conversation_id);
+    let conversation = app_handle
+        .state::<ConversationStore>()
+        .load_stored(conversation_id)
+        .map_err(|err| err.to_string())?;
+    let directory = configs::pick_directory()?;
+    let path = write_export(&app_handle, &directory, &conversation, format)?;
+    return Ok(path.to_string_lossy().to_string());
+}
+
+/// Writes every saved conversation, one file each, to a folder the user
+/// picks and returns the folder.
+#[tauri::command]
+pub async fn export_all_conversations(
+    format: ExportFormat,
+    app_handle: tauri::AppHandle,
+) -> Result<String, String> {
+    println!("Command: export_all_conversations");
+    let store = app_handle.state::<ConversationStore>();
+    let summaries = store.list().map_err(|err| err.to_string())?;
+    let directory = configs::pick_directory()?;
+    for summary in summaries {
+        let conversation = store
+            .load_stored(summary.id)
+            .map_err(|err| err.to_string())?;
+        write_export(&app_handle, &directory, &conversation, format)?;
+    }
+    return Ok(directory.to_string_lossy().to_string());
+}
+
+fn write_export(
+    app_handle: &tauri::AppHandle,
+    directory: &Path,
+    conversation: &StoredConversation,
+    format: ExportFormat,
+) -> Result<PathBuf, String> {
+    let model = model_metadata(app_handle, &conversation.summary.model_filename);
+    let contents = match format {
+        ExportFormat::Markdown => to_markdown(conversation, &model),
+        ExportFormat::Html => to_html(conversation, &model),
+        ExportFormat::Json => serde_json::to_string_pretty(&JsonExport {
+            format: JSON_FORMAT,
+            version: JSON_VERSION,
+            exported_at: conversation_store::now(),
+            model,
+            conversation,
+        })
+        .map_err(|err| err.to_string())?,
+    };
+    let path = available_path(directory, &file_stem(conversation), format.extension());
+    fs::write(&path, contents).map_err(|err| err.to_string())?;
+    return Ok(path);
+}
+
+fn model_metadata(app_handle: &tauri::AppHandle, model_filename: &str) -> ModelMetadata {
+    let catalog_model = profiles::find_catalog_model(app_handle, model_filename);
+    return ModelMetadata {
+        filename: model_filename.to_string(),
+        name: catalog_model.as_ref().map(|model| model.name.clone()),
+        arquitecture: catalog_model
+            .as_ref()
+            .map(|model| model.arquitecture.clone()),
+        url: catalog_model.map(|model| model.url),
+    };
+}
+
+/// The selected branch as Markdown, one section per message.
+fn to_markdown(conversation: &StoredConversation, model: &ModelMetadata) -> String {
+    let mut markdown = format!("# {}\n\n", conversation.summary.title);
+    markdown.push_str(&format!("- Model: {}\n", model_label(model)));
+    markdown.push_str(&format!(
+        "- Started: {}\n",
+        format_timestamp(conversation.summary.created_at)
+    ));
+    for stored in conversation.selected_path() {
+        markdown.push_str(&format!(
+            "\n## {}\n\n{}\n",
+            role_label(&stored.message.role),
+            stored.message.text.trim()
+        ));
+    }
+    return markdown;
+}
+
+/// The selected branch as a page that needs no other files.
+fn to_html(conversation: &StoredConversation, model: &ModelMetadata) -> String {
+    let mut messages = String::new();
+    for stored in conversation.selected_path() {
+        let class = match stored.message.role {
+            MessageRole::Human => "human",
+            MessageRole::AI => "ai",
+        };
+        messages.push_str(&format!(
+            "<section class=\"{}\">\n<h2>{}</h2>\n<p>{}</p>\n</section>\n",
+            class,
+            role_label(&stored.message.role),
+            escape_html(stored.message.text.trim())
+        ));
+    }
+    return format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; max-width: 48rem; margin: 2rem auto; padding: 0 1rem; }}
+section {{ border-radius: 0.5rem; padding: 0.5rem 1rem; margin: 1rem 0; }}
+section.human {{ background: #eef2ff; }}
+section.ai {{ background: #f4f4f5; }}
+h2 {{ font-size: 0.8rem; text-transform: uppercase; margin: 0.5rem 0; }}
+p {{ white-space: pre-wrap; }}
+</style>
+</head>
+<body>
+<h1>{title}</h1>
+<p>Model: {model}<br>Started: {started}</p>
+{messages}</body>
+</html>
+"#,
+        title = escape_html(&conversation.summary.title),
+        model = escape_html(&model_label(model)),
+        started = format_timestamp(conversation.summary.created_at),
+        messages = messages
+    );
+}
+
+fn model_label(model: &ModelMetadata) -> String {
+    match &model.name {
+        Some(name) => format!("{} ({})", name, model.filename),
+        None => model.filename.clone(),
+    }
+}
+
+fn role_label(role: &MessageRole) -> &'static str {
+    match role {
+        MessageRole::Human => "Human",
+        MessageRole::AI => "AI",
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    return text
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;");
+}
+
+/// The title, without the characters file systems don't allow.
+fn file_stem(conversation: &StoredConversation) -> String {
+    let stem: String = conversation
+        .summary
+        .title
+        .chars()
+        .filter(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_' | '.'))
+        .collect();
+    let stem = stem.trim().trim_matches('.');
+    if stem.is_empty() {
+        return format!("conversation-{}", conversation.summary.id);
+    }
+    return stem.to_string();
+}
+
+/// A path in the directory that doesn't exist yet, numbering the file when
+/// the name is taken.
+fn available_path(directory: &Path, stem: &str, extension: &str) -> PathBuf {
+    let mut path = directory.join(format!("{}.{}", stem, extension));
+    let mut number = 2;
+    while path.exists() {
+        path = directory.join(format!("{} ({}).{}", stem, number, extension));
+        number += 1;
+    }
+    return path;
+}
+
+/// `YYYY-MM-DD HH:MM UTC`, from seconds since the Unix epoch.
+fn format_timestamp(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86_400);
+    let seconds = timestamp.rem_euclid(86_400);
+    // Civil date from days since the epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    return format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conversation_store::ConversationSummary;
+
+    use super::*;
+
+    fn titled(id: ConversationId, title: &str) -> StoredConversation {
+        return StoredConversation {
+            summary: ConversationSummary {
+                id,
+                title: title.to_string(),
+                model_filename: "model.bin".to_string(),
+                created_at: 0,
+                updated_at: 0,
+            },
+            parameters: serde_json::Value::Null,
+            selected_message: None,
+            messages: Vec::new(),
+        };
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00 UTC");
+        // Leap days, 2000 is a leap year and 1900 isn't
+        assert_eq!(format_timestamp(1709210040), "2024-02-29 12:34 UTC");
+        assert_eq!(format_timestamp(951782400), "2000-02-29 00:00 UTC");
+        assert_eq!(format_timestamp(951868800), "2000-03-01 00:00 UTC");
+        assert_eq!(format_timestamp(-2203891200), "1900-03-01 00:00 UTC");
+        // Before 1970
+        assert_eq!(format_timestamp(-1), "1969-12-31 23:59 UTC");
+        assert_eq!(format_timestamp(-31536000), "1969-01-01 00:00 UTC");
+        assert_eq!(format_timestamp(-14182940), "1969-07-20 20:17 UTC");
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(escape_html("plain text"), "plain text");
+        assert_eq!(
+            escape_html("<script>alert(\"x\")</script>"),
+            "&lt;script&gt;alert(&quot;x&quot;)&lt;/script&gt;"
+        );
+        // Ampersands are escaped first, so entities show up as written
+        assert_eq!(escape_html("&lt; & >"), "&amp;lt; &amp; &gt;");
+    }
+
+    #[test]
+    fn names_files_after_the_title() {
+        assert_eq!(file_stem(&titled(1, "Rust: a/b? *test*")), "Rust ab test");
+        assert_eq!(
+            file_stem(&titled(1, "Grüße, v1.2_final")),
+            "Grüße v1.2_final"
+        );
+        assert_eq!(file_stem(&titled(1, "..hidden..")), "hidden");
+        assert_eq!(file_stem(&titled(3, "/:*?")), "conversation-3");
+        assert_eq!(file_stem(&titled(4, " ... ")), "conversation-4");
+    }
+
+    #[test]
+    fn numbers_files_whose_name_is_taken() {
+        let directory = std::env::temp_dir().join(format!("export-test-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let first = available_path(&directory, "Chat", "md");
+        assert_eq!(first, directory.join("Chat.md"));
+        fs::write(&first, "").unwrap();
+        let second = available_path(&directory, "Chat", "md");
+        assert_eq!(second, directory.join("Chat (2).md"));
+        fs::write(&second, "").unwrap();
+        assert_eq!(
+            available_path(&directory, "Chat", "md"),
+            directory.join("Chat (3).md")
+        );
+        // Other extensions don't clash
+        assert_eq!(
+            available_path(&directory, "Chat", "json"),
+            directory.join("Chat.json")
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,628 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Grammar syntax error on line {line}: {message}")]
+    Syntax { line: usize, message: String },
+    #[error("Rule {0} is used but never defined")]
+    UndefinedRule(String),
+    #[error("Rule {0} is defined twice")]
+    DuplicateRule(String),
+    #[error("The grammar needs a root rule")]
+    MissingRoot,
+    #[error("Rule {0} is left recursive, which isn't supported")]
+    LeftRecursion(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Element {
+    /// One character inside the ranges, or outside of them when negated
+    Char {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Rule(usize),
+}
+
+impl Element {
+    fn literal(c: char) -> Self {
+        Element::Char {
+            ranges: vec![(c, c)],
+            negated: false,
+        }
+    }
+}
+
+type Sequence = Vec<Element>;
+
+/// A grammar in the GBNF format used by llama.cpp, for example:
+///
+/// ```text
+/// root   ::= answer "." ws
+/// answer ::= "yes" | "no" | [0-9]+
+/// ws     ::= [ \t\n]*
+/// ```
+///
+/// Rules can use literals, character classes (`[a-z]`, `[^"]`), `.` for any
+/// character, groups and the `*`, `+` and `?` operators. Generation starts
+/// from the `root` rule.
+#[derive(Debug)]
+pub struct Grammar {
+    /// Alternatives of every rule
+    rules: Vec<Vec<Sequence>>,
+    rule_names: Vec<String>,
+    root: usize,
+}
+
+impl Grammar {
+    pub fn parse(text: &str) -> Result<Grammar> {
+        let mut parser = Parser {
+            chars: text.chars().collect(),
+            position: 0,
+            rule_ids: HashMap::new(),
+            rules: Vec::new(),
+            rule_names: Vec::new(),
+        };
+        parser.parse_rules()?;
+
+        let mut rules = Vec::new();
+        for (rule, alternatives) in parser.rules.into_iter().enumerate() {
+            match alternatives {
+                Some(alternatives) => rules.push(alternatives),
+                None => return Err(Error::UndefinedRule(parser.rule_names[rule].clone())),
+            }
+        }
+        let root = *parser.rule_ids.get("root").ok_or(Error::MissingRoot)?;
+        let grammar = Grammar {
+            rules,
+            rule_names: parser.rule_names,
+            root,
+        };
+        grammar.check_left_recursion()?;
+        return Ok(grammar);
+    }
+
+    fn element(&self, position: Position) -> &Element {
+        return &self.rules[position.rule][position.alternative][position.element];
+    }
+
+    fn next(&self, position: Position) -> Option<Position> {
+        let next = Position {
+            element: position.element + 1,
+            ..position
+        };
+        if next.element < self.rules[position.rule][position.alternative].len() {
+            return Some(next);
+        }
+        return None;
+    }
+
+    /// Left recursion would make the matcher expand rules forever, so it is
+    /// rejected up front.
+    fn check_left_recursion(&self) -> Result<()> {
+        let mut nullable = vec![false; self.rules.len()];
+        let is_nullable = |element: &Element, nullable: &[bool]| match element {
+            Element::Char { .. } => false,
+            Element::Rule(rule) => nullable[*rule],
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (rule, alternatives) in self.rules.iter().enumerate() {
+                if !nullable[rule]
+                    && alternatives.iter().any(|sequence| {
+                        sequence
+                            .iter()
+                            .all(|element| is_nullable(element, &nullable))
+                    })
+                {
+                    nullable[rule] = true;
+                    changed = true;
+                }
+            }
+        }
+
+        // Rules that can be expanded without consuming a character
+        let mut leading_rules = vec![Vec::new(); self.rules.len()];
+        for (rule, alternatives) in self.rules.iter().enumerate() {
+            for sequence in alternatives {
+                for element in sequence {
+                    if let Element::Rule(leading_rule) = element {
+                        leading_rules[rule].push(*leading_rule);
+                    }
+                    if !is_nullable(element, &nullable) {
+                        break;
+                    }
+                }
+            }
+        }
+
+        for start in 0..self.rules.len() {
+            let mut visited = vec![false; self.rules.len()];
+            let mut pending = leading_rules[start].clone();
+            while let Some(rule) = pending.pop() {
+                if rule == start {
+                    return Err(Error::LeftRecursion(self.rule_names[start].clone()));
+                }
+                if !visited[rule] {
+                    visited[rule] = true;
+                    pending.extend(leading_rules[rule].iter().copied());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    rule_ids: HashMap<String, usize>,
+    /// `None` until the rule is defined
+    rules: Vec<Option<Vec<Sequence>>>,
+    rule_names: Vec<String>,
+}
+
+impl Parser {
+    fn parse_rules(&mut self) -> Result<()> {
+        loop {
+            self.skip_whitespace();
+            if self.peek().is_none() {
+                return Ok(());
+            }
+            let name = self.parse_name()?;
+            self.skip_whitespace();
+            self.expect_str("::=")?;
+            let alternatives = self.parse_alternatives(&name)?;
+
+            let rule = self.rule_id(&name);
+            if self.rules[rule].is_some() {
+                return Err(Error::DuplicateRule(name));
+            }
+            self.rules[rule] = Some(alternatives);
+        }
+    }
+
+    fn parse_alternatives(&mut self, rule_name: &str) -> Result<Vec<Sequence>> {
+        let mut alternatives = vec![self.parse_sequence(rule_name)?];
+        while self.peek() == Some('|') {
+            self.position += 1;
+            alternatives.push(self.parse_sequence(rule_name)?);
+        }
+        return Ok(alternatives);
+    }
+
+    fn parse_sequence(&mut self, rule_name: &str) -> Result<Sequence> {
+        let mut sequence = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.at_rule_start() {
+                return Ok(sequence);
+            }
+            let item = match self.peek() {
+                None | Some('|') | Some(')') => return Ok(sequence),
+                Some('"') => self.parse_literal()?,
+                Some('[') => vec![self.parse_class()?],
+                Some('.') => {
+                    self.position += 1;
+                    vec![Element::Char {
+                        ranges: Vec::new(),
+                        negated: true,
+                    }]
+                }
+                Some('(') => {
+                    self.position += 1;
+                    let alternatives = self.parse_alternatives(rule_name)?;
+                    self.skip_whitespace();
+                    self.expect_str(")")?;
+                    vec![Element::Rule(self.add_rule(rule_name, alternatives))]
+                }
+                Some(c) if is_name_char(c) => {
+                    let name = self.parse_name()?;
+                    vec![Element::Rule(self.rule_id(&name))]
+                }
+                Some(c) => return Err(self.syntax_error(format!("unexpected {:?}", c))),
+            };
+
+            match self.peek() {
+                Some(operator @ ('*' | '+' | '?')) => {
+                    self.position += 1;
+                    sequence.extend(self.repeat(rule_name, item, operator));
+                }
+                _ => sequence.extend(item),
+            }
+        }
+    }
+
+    /// Turns `item*`, `item+` and `item?` into plain rules.
+    fn repeat(&mut self, rule_name: &str, item: Sequence, operator: char) -> Sequence {
+        let element = if item.len() == 1 {
+            item[0].clone()
+        } else {
+            Element::Rule(self.add_rule(rule_name, vec![item]))
+        };
+        match operator {
+            '?' => {
+                let rule = self.add_rule(rule_name, vec![vec![element], vec![]]);
+                return vec![Element::Rule(rule)];
+            }
+            _ => {
+                let rule = self.add_rule(rule_name, Vec::new());
+                self.rules[rule] = Some(vec![vec![element.clone(), Element::Rule(rule)], vec![]]);
+                if operator == '+' {
+                    return vec![element, Element::Rule(rule)];
+                }
+                return vec![Element::Rule(rule)];
+            }
+        }
+    }
+
+    fn parse_literal(&mut self) -> Result<Sequence> {
+        self.expect_str("\"")?;
+        let mut sequence = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.syntax_error("unterminated literal".to_string())),
+                Some('"') => {
+                    self.position += 1;
+                    return Ok(sequence);
+                }
+                Some(_) => sequence.push(Element::literal(self.parse_char()?)),
+            }
+        }
+    }
+
+    fn parse_class(&mut self) -> Result<Element> {
+        self.expect_str("[")?;
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.position += 1;
+        }
+        let mut ranges = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.syntax_error("unterminated character class".to_string())),
+                Some(']') => {
+                    self.position += 1;
+                    return Ok(Element::Char { ranges, negated });
+                }
+                Some(_) => {
+                    let start = self.parse_char()?;
+                    let mut end = start;
+                    if self.peek() == Some('-') && self.chars.get(self.position + 1) != Some(&']') {
+                        self.position += 1;
+                        end = self.parse_char()?;
+                    }
+                    ranges.push((start, end));
+                }
+            }
+        }
+    }
+
+    fn parse_char(&mut self) -> Result<char> {
+        let c = self
+            .peek()
+            .ok_or_else(|| self.syntax_error("unexpected end".to_string()))?;
+        self.position += 1;
+        if c != '\\' {
+            return Ok(c);
+        }
+
+        let escaped = self
+            .peek()
+            .ok_or_else(|| self.syntax_error("unexpected end".to_string()))?;
+        self.position += 1;
+        let digits = match escaped {
+            'n' => return Ok('\n'),
+            'r' => return Ok('\r'),
+            't' => return Ok('\t'),
+            'x' => 2,
+            'u' => 4,
+            'U' => 8,
+            _ => return Ok(escaped),
+        };
+        let end = (self.position + digits).min(self.chars.len());
+        let hex: String = self.chars[self.position..end].iter().collect();
+        self.position = end;
+        return u32::from_str_radix(&hex, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| self.syntax_error(format!("invalid escape \\{}{}", escaped, hex)));
+    }
+
+    fn parse_name(&mut self) -> Result<String> {
+        let start = self.position;
+        while self.peek().map_or(false, is_name_char) {
+            self.position += 1;
+        }
+        if start == self.position {
+            return Err(self.syntax_error("expected a rule name".to_string()));
+        }
+        return Ok(self.chars[start..self.position].iter().collect());
+    }
+
+    /// Whether the next tokens are `name ::=`, which ends the current rule.
+    fn at_rule_start(&mut self) -> bool {
+        let start = self.position;
+        let found = self.parse_name().is_ok() && {
+            self.skip_whitespace();
+            self.expect_str("::=").is_ok()
+        };
+        self.position = start;
+        return found;
+    }
+
+    fn expect_str(&mut self, expected: &str) -> Result<()> {
+        for c in expected.chars() {
+            if self.peek() != Some(c) {
+                return Err(self.syntax_error(format!("expected {:?}", expected)));
+            }
+            self.position += 1;
+        }
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while self.peek().map_or(false, |c| c != '\n') {
+                    self.position += 1;
+                }
+            } else if c.is_whitespace() {
+                self.position += 1;
+            } else {
+                return;
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        return self.chars.get(self.position).copied();
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        if let Some(rule) = self.rule_ids.get(name) {
+            return *rule;
+        }
+        let rule = self.rules.len();
+        self.rules.push(None);
+        self.rule_names.push(name.to_string());
+        self.rule_ids.insert(name.to_string(), rule);
+        return rule;
+    }
+
+    /// Adds an unnamed rule, used for groups and repetitions.
+    fn add_rule(&mut self, rule_name: &str, alternatives: Vec<Sequence>) -> usize {
+        let rule = self.rules.len();
+        self.rules.push(Some(alternatives));
+        self.rule_names.push(format!("{}-{}", rule_name, rule));
+        return rule;
+    }
+
+    fn syntax_error(&self, message: String) -> Error {
+        let line = self.chars[..self.position.min(self.chars.len())]
+            .iter()
+            .filter(|c| **c == '\n')
+            .count()
+            + 1;
+        return Error::Syntax { line, message };
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    return c.is_ascii_alphanumeric() || c == '-' || c == '_';
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Position {
+    rule: usize,
+    alternative: usize,
+    element: usize,
+}
+
+/// Positions still to be matched, the next one last. An empty stack means the
+/// grammar has been matched completely.
+type Stack = Vec<Position>;
+
+/// Tracks which text the grammar still allows after what was generated so
+/// far. Every possible parse is kept as a stack of positions in the grammar.
+#[derive(Debug, Clone)]
+pub struct GrammarMatcher {
+    grammar: Arc<Grammar>,
+    stacks: Vec<Stack>,
+}
+
+impl GrammarMatcher {
+    pub fn new(grammar: Arc<Grammar>) -> Self {
+        let mut stacks = Vec::new();
+        for (alternative, sequence) in grammar.rules[grammar.root].iter().enumerate() {
+            let mut stack = Vec::new();
+            if !sequence.is_empty() {
+                stack.push(Position {
+                    rule: grammar.root,
+                    alternative,
+                    element: 0,
+                });
+            }
+            expand(&grammar, stack, &mut stacks);
+        }
+        stacks.sort();
+        stacks.dedup();
+        GrammarMatcher { grammar, stacks }
+    }
+
+    /// Whether the text generated so far is a complete match.
+    pub fn is_complete(&self) -> bool {
+        return self.stacks.iter().any(|stack| stack.is_empty());
+    }
+
+    /// Whether the grammar allows `text` to come next.
+    pub fn accepts(&self, text: &str) -> bool {
+        let mut chars = text.chars();
+        let mut stacks = match chars.next() {
+            Some(c) => accept_char(&self.grammar, &self.stacks, c),
+            None => return true,
+        };
+        for c in chars {
+            if stacks.is_empty() {
+                return false;
+            }
+            stacks = accept_char(&self.grammar, &stacks, c);
+        }
+        return !stacks.is_empty();
+    }
+
+    /// Moves past `text`, which should have been checked with [`accepts`].
+    ///
+    /// [`accepts`]: GrammarMatcher::accepts
+    pub fn advance(&mut self, text: &str) {
+        for c in text.chars() {
+            self.stacks = accept_char(&self.grammar, &self.stacks, c);
+        }
+    }
+}
+
+/// Expands the rule on top of the stack until a character is next, adding a
+/// stack for every alternative.
+fn expand(grammar: &Grammar, stack: Stack, stacks: &mut Vec<Stack>) {
+    let mut pending = vec![stack];
+    while let Some(mut stack) = pending.pop() {
+        let top = match stack.last() {
+            Some(top) => *top,
+            None => {
+                stacks.push(stack);
+                continue;
+            }
+        };
+        match grammar.element(top) {
+            Element::Char { .. } => stacks.push(stack),
+            Element::Rule(rule) => {
+                stack.pop();
+                if let Some(next) = grammar.next(top) {
+                    stack.push(next);
+                }
+                for (alternative, sequence) in grammar.rules[*rule].iter().enumerate() {
+                    let mut expanded = stack.clone();
+                    if !sequence.is_empty() {
+                        expanded.push(Position {
+                            rule: *rule,
+                            alternative,
+                            element: 0,
+                        });
+                    }
+                    pending.push(expanded);
+                }
+            }
+        }
+    }
+}
+
+fn accept_char(grammar: &Grammar, stacks: &[Stack], c: char) -> Vec<Stack> {
+    let mut accepted = Vec::new();
+    for stack in stacks {
+        let top = match stack.last() {
+            Some(top) => *top,
+            None => continue,
+        };
+        if let Element::Char { ranges, negated } = grammar.element(top) {
+            let in_ranges = ranges
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&c));
+            if in_ranges != *negated {
+                let mut next_stack = stack.clone();
+                next_stack.pop();
+                if let Some(next) = grammar.next(top) {
+                    next_stack.push(next);
+                }
+                expand(grammar, next_stack, &mut accepted);
+            }
+        }
+    }
+    accepted.sort();
+    accepted.dedup();
+    return accepted;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether the grammar matches the whole text.
+    fn matches(grammar: &str, text: &str) -> bool {
+        let grammar = Arc::new(Grammar::parse(grammar).unwrap());
+        let mut matcher = GrammarMatcher::new(grammar);
+        if !matcher.accepts(text) {
+            return false;
+        }
+        matcher.advance(text);
+        return matcher.is_complete();
+    }
+
+    const ANSWER: &str = r#"
+        root   ::= answer "." ws
+        answer ::= "yes" | "no" | [0-9]+
+        ws     ::= [ \t\n]*
+    "#;
+
+    #[test]
+    fn matches_alternatives_and_repetitions() {
+        assert!(matches(ANSWER, "yes."));
+        assert!(matches(ANSWER, "42. \n"));
+        assert!(!matches(ANSWER, "maybe."));
+        assert!(!matches(ANSWER, "."));
+    }
+
+    #[test]
+    fn accepts_prefixes_until_complete() {
+        let grammar = Arc::new(Grammar::parse(ANSWER).unwrap());
+        let mut matcher = GrammarMatcher::new(grammar);
+        assert!(matcher.accepts("ye"));
+        assert!(!matcher.accepts("ya"));
+        matcher.advance("no");
+        assert!(!matcher.is_complete());
+        assert!(!matcher.accepts("s"));
+        matcher.advance(".");
+        assert!(matcher.is_complete());
+        assert!(matcher.accepts(" "));
+    }
+
+    #[test]
+    fn parses_classes_escapes_groups_and_comments() {
+        let grammar = r#"
+            # A quoted word, then an optional number
+            root ::= "\"" [^"\x00-\x1f]+ "\"" ( " " [0-9] )? .
+        "#;
+        assert!(matches(grammar, "\"hi\"!"));
+        assert!(matches(grammar, "\"hi\" 7!"));
+        assert!(!matches(grammar, "\"\"!"));
+        assert!(!matches(grammar, "\"hi\" 7"));
+        assert!(matches(r#"root ::= "é" [a-c-]"#, "é-"));
+    }
+
+    #[test]
+    fn rejects_invalid_grammars() {
+        assert!(matches!(
+            Grammar::parse("root ::= a\na ::= \"x\"\na ::= \"y\""),
+            Err(Error::DuplicateRule(name)) if name == "a"
+        ));
+        assert!(matches!(
+            Grammar::parse("root ::= missing"),
+            Err(Error::UndefinedRule(name)) if name == "missing"
+        ));
+        assert!(matches!(
+            Grammar::parse("answer ::= \"x\""),
+            Err(Error::MissingRoot)
+        ));
+        assert!(matches!(
+            Grammar::parse("root ::= list\nlist ::= list \",\" \"x\" | \"x\""),
+            Err(Error::LeftRecursion(_))
+        ));
+        assert!(matches!(
+            Grammar::parse("root ::= \"x\"\nother ::= \"unterminated"),
+            Err(Error::Syntax { line: 2, .. })
+        ));
+    }
+}
@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde_json::Value;
+use tauri::Manager;
+
+use crate::conversation::{Message, MessageId, MessageRole};
+use crate::conversation_store::{self, ConversationStore, NewConversation, StoredMessage};
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Store(#[from] conversation_store::Error),
+    #[error("Not a ChatGPT export or a list of messages")]
+    UnknownFormat,
+    #[error("There are no messages to import")]
+    NoMessages,
+    #[error("Line {line}: {message}")]
+    InvalidLine { line: usize, message: String },
+    #[error("Message {index}: {message}")]
+    InvalidMessage { index: usize, message: String },
+}
+
+/// Adds the conversations in a file the user picks to the conversation
+/// store, and returns how many there were. Reads ChatGPT's
+/// `conversations.json` export, and lists of `{ role, content }` messages as
+/// JSONL, as a JSON array or in the `messages` field of an object.
+#[tauri::command]
+pub async fn import_conversations(
+    app_handle: tauri::AppHandle,
+) -> std::result::Result<usize, String> {
+    use tauri::api::dialog::blocking::FileDialogBuilder;
+
+    let dialog_result = FileDialogBuilder::new()
+        .add_filter("JSON", &["json", "jsonl"])
+        .pick_file();
+
+    match dialog_result {
+        Some(path) => {
+            println!("Command: import_conversations, path:{}", path.display());
+            let conversations = read_conversations(&path).map_err(|err| err.to_string())?;
+            let store = app_handle.state::<ConversationStore>();
+            store
+                .insert_all(&conversations)
+                .map_err(|err| err.to_string())?;
+            return Ok(conversations.len());
+        }
+        None => {
+            return Err("No file selected".to_string());
+        }
+    }
+}
+
+fn read_conversations(path: &Path) -> Result<Vec<NewConversation>> {
+    let contents = fs::read_to_string(path)?;
+    let title = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| "Imported conversation".to_string());
+
+    return match serde_json::from_str::<Value>(&contents) {
+        Ok(Value::Array(items)) if items.iter().any(|item| item.get("mapping").is_some()) => {
+            Ok(chatgpt_conversations(&items))
+        }
+        Ok(Value::Array(items)) => Ok(vec![messages_conversation(&items, &title)?]),
+        // A JSONL file with a single line
+        Ok(Value::Object(object)) if object.contains_key("role") => {
+            Ok(vec![messages_conversation(
+                &[Value::Object(object)],
+                &title,
+            )?])
+        }
+        Ok(Value::Object(object)) => match object.get("messages") {
+            Some(Value::Array(items)) => Ok(vec![messages_conversation(items, &title)?]),
+            _ => Err(Error::UnknownFormat),
+        },
+        Ok(_) => Err(Error::UnknownFormat),
+        // Not a single JSON value, so one message per line
+        Err(_) => Ok(vec![jsonl_conversation(&contents, &title)?]),
+    };
+}
+
+/// Conversations of a ChatGPT export. Every branch is kept, system and tool
+/// messages are left out.
+fn chatgpt_conversations(items: &[Value]) -> Vec<NewConversation> {
+    return items
+        .iter()
+        .filter_map(chatgpt_conversation)
+        .filter(|conversation| !conversation.messages.is_empty())
+        .collect();
+}
+
+fn chatgpt_conversation(item: &Value) -> Option<NewConversation> {
+    let mapping = item.get("mapping")?.as_object()?;
+    let created_at = item
+        .get("create_time")
+        .and_then(Value::as_f64)
+        .map(|time| time as i64)
+        .unwrap_or_else(conversation_store::now);
+
+    // Walks the tree from the roots, so parents get lower ids than their
+    // replies. Nodes that are left out pass their parent on to their children.
+    let mut messages = Vec::new();
+    let mut kept_ancestors: HashMap<&str, Option<MessageId>> = HashMap::new();
+    let mut pending: Vec<(&str, Option<MessageId>)> = mapping
+        .iter()
+        .filter(|(_, node)| {
+            node.get("parent")
+                .and_then(Value::as_str)
+                .map_or(true, |parent| !mapping.contains_key(parent))
+        })
+        .map(|(node_id, _)| (node_id.as_str(), None))
+        .rev()
+        .collect();
+    while let Some((node_id, parent)) = pending.pop() {
+        let node = match mapping.get(node_id) {
+            Some(node) => node,
+            None => continue,
+        };
+        let kept = match node.get("message").and_then(chatgpt_message) {
+            Some((role, text, time)) => {
+                let id = messages.len() as MessageId;
+                messages.push(StoredMessage {
+                    message: Message {
+                        id,
+                        parent,
+                        text,
+                        role,
+                    },
+                    created_at: time.unwrap_or(created_at),
+                });
+                Some(id)
+            }
+            None => parent,
+        };
+        kept_ancestors.insert(node_id, kept);
+        if let Some(children) = node.get("children").and_then(Value::as_array) {
+            for child in children.iter().rev().filter_map(Value::as_str) {
+                pending.push((child, kept));
+            }
+        }
+    }
+
+    let selected_message = item
+        .get("current_node")
+        .and_then(Value::as_str)
+        .and_then(|node_id| kept_ancestors.get(node_id).copied().flatten())
+        .or_else(|| messages.last().map(|stored| stored.message.id));
+    return Some(NewConversation {
+        title: item
+            .get("title")
+            .and_then(Value::as_str)
+            .filter(|title| !title.trim().is_empty())
+            .unwrap_or("Imported conversation")
+            .to_string(),
+        model_filename: item
+            .get("default_model_slug")
+            .and_then(Value::as_str)
+            .unwrap_or("chatgpt")
+            .to_string(),
+        created_at,
+        messages,
+        selected_message,
+    });
+}
+
+/// Role, text and time of a ChatGPT message, `None` for messages that are
+/// left out.
+fn chatgpt_message(message: &Value) -> Option<(MessageRole, String, Option<i64>)> {
+    let role = match message.get("author")?.get("role")?.as_str()? {
+        "user" => MessageRole::Human,
+        "assistant" => MessageRole::AI,
+        _ => return None,
+    };
+    let content = message.get("content")?;
+    let text = match content.get("parts").and_then(Value::as_array) {
+        // Images and other attachments are objects, only the text is kept
+        Some(parts) => parts
+            .iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join("\n"),
+        None => content.get("text")?.as_str()?.to_string(),
+    };
+    if text.trim().is_empty() {
+        return None;
+    }
+    let time = message
+        .get("create_time")
+        .and_then(Value::as_f64)
+        .map(|time| time as i64);
+    return Some((role, text, time));
+}
+
+fn jsonl_conversation(contents: &str, title: &str) -> Result<NewConversation> {
+    let mut items = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let item: Value = serde_json::from_str(line).map_err(|err| Error::InvalidLine {
+            line: index + 1,
+            message: err.to_string(),
+        })?;
+        items.push(item);
+    }
+    return messages_conversation(&items, title);
+}
+
+/// A conversation from `{ role, content }` messages, one after the other.
+/// System messages are left out.
+fn messages_conversation(items: &[Value], title: &str) -> Result<NewConversation> {
+    let created_at = conversation_store::now();
+    let mut messages: Vec<StoredMessage> = Vec::new();
+    for (index, item) in items.iter().enumerate() {
+        let invalid = |message: &str| Error::InvalidMessage {
+            index: index + 1,
+            message: message.to_string(),
+        };
+        let role = item
+            .get("role")
+            .and_then(Value::as_str)
+            .ok_or_else(|| invalid("missing role"))?;
+        let role = match role.to_lowercase().as_str() {
+            "user" | "human" => MessageRole::Human,
+            "assistant" | "ai" | "bot" | "model" => MessageRole::AI,
+            "system" => continue,
+            _ => return Err(invalid(&format!("unknown role {}", role))),
+        };
+        let text = item
+            .get("content")
+            .and_then(Value::as_str)
+            .ok_or_else(|| invalid("missing content"))?;
+        let id = messages.len() as MessageId;
+        messages.push(StoredMessage {
+            message: Message {
+                id,
+                parent: id.checked_sub(1),
+                text: text.to_string(),
+                role,
+            },
+            created_at,
+        });
+    }
+    if messages.is_empty() {
+        return Err(Error::NoMessages);
+    }
+
+    return Ok(NewConversation {
+        title: title.to_string(),
+        model_filename: "imported".to_string(),
+        created_at,
+        selected_message: messages.last().map(|stored| stored.message.id),
+        messages,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        return Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/importers")
+            .join(name);
+    }
+
+    /// Role, text and parent of each message.
+    fn messages(conversation: &NewConversation) -> Vec<(MessageRole, &str, Option<MessageId>)> {
+        return conversation
+            .messages
+            .iter()
+            .map(|stored| {
+                let message = &stored.message;
+                (message.role.clone(), message.text.as_str(), message.parent)
+            })
+            .collect();
+    }
+
+    #[test]
+    fn keeps_every_branch_of_chatgpt_exports() {
+        let conversations = read_conversations(&fixture("chatgpt_branched.json")).unwrap();
+        // The conversation with only a system message is left out
+        assert_eq!(conversations.len(), 2);
+
+        let borrowing = &conversations[0];
+        assert_eq!(borrowing.title, "Borrowing");
+        assert_eq!(borrowing.model_filename, "gpt-4");
+        assert_eq!(borrowing.created_at, 1700000000);
+        assert_eq!(
+            messages(borrowing),
+            vec![
+                (MessageRole::Human, "What is borrowing?", None),
+                (MessageRole::AI, "First answer", Some(0)),
+                (MessageRole::AI, "Second answer", Some(0)),
+            ]
+        );
+        assert_eq!(borrowing.messages[2].created_at, 1700000003);
+        // The current node is a tool message, its kept parent is selected
+        assert_eq!(borrowing.selected_message, Some(2));
+    }
+
+    #[test]
+    fn selects_the_last_message_when_the_current_node_has_no_kept_ancestor() {
+        let conversations = read_conversations(&fixture("chatgpt_branched.json")).unwrap();
+        let hello = &conversations[1];
+        assert_eq!(hello.title, "Imported conversation");
+        assert_eq!(hello.model_filename, "chatgpt");
+        assert_eq!(
+            messages(hello),
+            vec![
+                (MessageRole::Human, "Hello", None),
+                (MessageRole::AI, "Hi", Some(0)),
+            ]
+        );
+        // Messages without a time take the one of the conversation
+        assert_eq!(hello.messages[1].created_at, 1700000100);
+        assert_eq!(hello.selected_message, Some(1));
+    }
+
+    #[test]
+    fn reads_one_message_per_line() {
+        let conversations = read_conversations(&fixture("messages.jsonl")).unwrap();
+        assert_eq!(conversations.len(), 1);
+        let conversation = &conversations[0];
+        assert_eq!(conversation.title, "messages");
+        assert_eq!(
+            messages(conversation),
+            vec![
+                (MessageRole::Human, "Name a color", None),
+                (MessageRole::AI, "Blue", Some(0)),
+                (MessageRole::Human, "Another", Some(1)),
+            ]
+        );
+        assert_eq!(conversation.selected_message, Some(2));
+
+        let single_line = read_conversations(&fixture("single_line.jsonl")).unwrap();
+        assert_eq!(
+            messages(&single_line[0]),
+            vec![(MessageRole::Human, "Only one line", None)]
+        );
+    }
+
+    #[test]
+    fn reads_messages_in_arrays_and_objects() {
+        let array = read_conversations(&fixture("messages.json")).unwrap();
+        assert_eq!(
+            messages(&array[0]),
+            vec![
+                (MessageRole::Human, "Question", None),
+                (MessageRole::AI, "Answer", Some(0)),
+            ]
+        );
+
+        let object = read_conversations(&fixture("messages_object.json")).unwrap();
+        assert_eq!(object[0].title, "messages_object");
+        assert_eq!(
+            messages(&object[0]),
+            vec![(MessageRole::Human, "Question", None)]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_formats_and_invalid_messages() {
+        assert!(matches!(
+            read_conversations(&fixture("unknown.json")),
+            Err(Error::UnknownFormat)
+        ));
+        assert!(matches!(
+            read_conversations(&fixture("invalid_line.jsonl")),
+            Err(Error::InvalidLine { line: 2, .. })
+        ));
+        assert!(matches!(
+            read_conversations(&fixture("unknown_role.json")),
+            Err(Error::InvalidMessage { index: 2, .. })
+        ));
+        assert!(matches!(
+            read_conversations(&fixture("missing.json")),
+            Err(Error::Io(_))
+        ));
+    }
+}
@@ -0,0 +1,72 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tauri::Manager;
+
+use crate::conversation_store::ConversationStore;
+use crate::localstore;
+
+/// How many completions are kept per model
+const HISTORY_LENGTH: usize = 50;
+
+/// Token counts and timings of a single completion.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct CompletionStats {
+    pub prompt_tokens: usize,
+    pub generated_tokens: usize,
+    pub feed_prompt_ms: u64,
+    pub predict_ms: u64,
+    pub tokens_per_second: f64,
+    /// Seconds since the Unix epoch
+    pub finished_at: u64,
+}
+
+impl CompletionStats {
+    pub fn new(stats: &llm::InferenceStats) -> Self {
+        let predict_seconds = stats.predict_duration.as_secs_f64();
+        CompletionStats {
+            prompt_tokens: stats.prompt_tokens,
+            generated_tokens: stats.predict_tokens,
+            feed_prompt_ms: millis(stats.feed_prompt_duration),
+            predict_ms: millis(stats.predict_duration),
+            tokens_per_second: if predict_seconds > 0.0 {
+                stats.predict_tokens as f64 / predict_seconds
+            } else {
+                0.0
+            },
+            finished_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// Adds the stats to the history of the current model, dropping the oldest
+/// entries past [`HISTORY_LENGTH`].
+pub fn record(app_handle: &tauri::AppHandle, stats: &CompletionStats) {
+    let model_filename = localstore::get_current_model_filename(app_handle.clone());
+    let store = app_handle.state::<ConversationStore>();
+    if let Err(err) = store.add_inference_stats(&model_filename, stats, HISTORY_LENGTH) {
+        println!("Error saving inference stats: {}", err);
+    }
+}
+
+/// Recent completion stats of a model, oldest first. Defaults to the current
+/// model.
+#[tauri::command]
+pub fn get_inference_stats(
+    model_filename: Option<String>,
+    app_handle: tauri::AppHandle,
+) -> Result<Vec<CompletionStats>, String> {
+    println!("Command: get_inference_stats");
+    let model_filename = model_filename
+        .unwrap_or_else(|| localstore::get_current_model_filename(app_handle.clone()));
+    let store = app_handle.state::<ConversationStore>();
+    return store
+        .inference_stats(&model_filename)
+        .map_err(|err| err.to_string());
+}
+
+fn millis(duration: Duration) -> u64 {
+    return duration.as_millis() as u64;
+}
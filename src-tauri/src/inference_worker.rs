@@ -0,0 +1,374 @@
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use tokio::sync::oneshot;
+
+pub type JobId = u64;
+
+/// What the worker thread owns and lends to every job it runs.
+pub struct WorkerContext {
+    pub model: Option<Box<dyn llm::Model>>,
+}
+
+/// Handed to a job while it runs.
+pub struct RunningJob {
+    pub id: JobId,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl RunningJob {
+    pub fn is_cancelled(&self) -> bool {
+        return self.cancelled.load(Ordering::SeqCst);
+    }
+}
+
+/// Runs a job and sends its result. Returns the panic message if it panicked.
+type JobFn = Box<dyn FnOnce(&mut WorkerContext, &RunningJob) -> Result<(), String> + Send>;
+
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum JobError {
+    /// Cancelled before the worker started on it
+    #[error("The request was cancelled")]
+    Cancelled,
+    #[error("The request failed: {0}")]
+    Panicked(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobPriority {
+    /// Requests the user waits for
+    Normal,
+    /// Background work. Only starts when no normal job waits, and is
+    /// cancelled when one is submitted while it runs.
+    Low,
+}
+
+struct Job {
+    id: JobId,
+    cancelled: Arc<AtomicBool>,
+    run: JobFn,
+}
+
+#[derive(Default)]
+struct JobRegistry {
+    waiting: VecDeque<Job>,
+    waiting_low: VecDeque<Job>,
+    running: Option<(JobId, JobPriority)>,
+    cancel_flags: HashMap<JobId, Arc<AtomicBool>>,
+}
+
+impl JobRegistry {
+    fn next_job(&mut self) -> Option<(Job, JobPriority)> {
+        if let Some(job) = self.waiting.pop_front() {
+            return Some((job, JobPriority::Normal));
+        }
+        return self
+            .waiting_low
+            .pop_front()
+            .map(|job| (job, JobPriority::Low));
+    }
+}
+
+/// Runs every job that needs the model on a dedicated thread.
+///
+/// Jobs are queued and run one at a time, normal jobs in the order they were
+/// submitted and then low priority ones, so Tauri commands only await the
+/// result and never block a runtime thread while the model is busy.
+pub struct InferenceWorker {
+    next_id: AtomicU64,
+    registry: Arc<(Mutex<JobRegistry>, Condvar)>,
+}
+
+/// Result of a submitted job. Resolves to an error when the job was
+/// cancelled before it started or panicked.
+pub struct JobHandle<T> {
+    pub id: JobId,
+    receiver: oneshot::Receiver<Result<T, JobError>>,
+}
+
+impl<T> JobHandle<T> {
+    pub async fn wait(self) -> Result<T, JobError> {
+        // Jobs cancelled before starting are dropped without sending
+        return self.receiver.await.unwrap_or(Err(JobError::Cancelled));
+    }
+}
+
+impl InferenceWorker {
+    pub fn start(model: Option<Box<dyn llm::Model>>) -> Self {
+        let registry = Arc::new((Mutex::new(JobRegistry::default()), Condvar::new()));
+
+        let worker_registry = Arc::clone(&registry);
+        thread::Builder::new()
+            .name("inference-worker".to_string())
+            .spawn(move || run_jobs(worker_registry, WorkerContext { model }))
+            .expect("failed to spawn the inference worker");
+
+        InferenceWorker {
+            next_id: AtomicU64::new(1),
+            registry,
+        }
+    }
+
+    /// Queues a job. It receives the worker context and its own id, which
+    /// also tells whether it was cancelled while running.
+    pub fn submit<T, F>(&self, job: F) -> JobHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut WorkerContext, &RunningJob) -> T + Send + 'static,
+    {
+        return self.submit_with_priority(JobPriority::Normal, job);
+    }
+
+    pub fn submit_with_priority<T, F>(&self, priority: JobPriority, job: F) -> JobHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut WorkerContext, &RunningJob) -> T + Send + 'static,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (result_sender, receiver) = oneshot::channel();
+        let run: JobFn = Box::new(move |context, running_job| {
+            // A panic in the model, a sampler or a grammar ends this job only.
+            // Whatever the job held is dropped while unwinding.
+            match panic::catch_unwind(AssertUnwindSafe(|| job(context, running_job))) {
+                Ok(value) => {
+                    let _ = result_sender.send(Ok(value));
+                    return Ok(());
+                }
+                Err(payload) => {
+                    let message = panic_message(payload.as_ref());
+                    let _ = result_sender.send(Err(JobError::Panicked(message.clone())));
+                    return Err(message);
+                }
+            }
+        });
+
+        let (registry, job_queued) = &*self.registry;
+        let mut registry = registry.lock().unwrap();
+        registry.cancel_flags.insert(id, Arc::clone(&cancelled));
+        let job = Job { id, cancelled, run };
+        match priority {
+            JobPriority::Normal => {
+                // Background work gives way to the user
+                if let Some((running_id, JobPriority::Low)) = registry.running {
+                    if let Some(running_cancelled) = registry.cancel_flags.get(&running_id) {
+                        running_cancelled.store(true, Ordering::SeqCst);
+                    }
+                }
+                registry.waiting.push_back(job);
+            }
+            JobPriority::Low => registry.waiting_low.push_back(job),
+        }
+        job_queued.notify_one();
+
+        return JobHandle { id, receiver };
+    }
+
+    /// How many jobs run before this one, `0` meaning it is running now.
+    /// `None` when the job already finished or doesn't exist.
+    pub fn queue_position(&self, id: JobId) -> Option<usize> {
+        let registry = self.registry.0.lock().unwrap();
+        let running = match registry.running {
+            Some((running_id, _)) if running_id == id => return Some(0),
+            Some(_) => 1,
+            None => 0,
+        };
+        return registry
+            .waiting
+            .iter()
+            .chain(registry.waiting_low.iter())
+            .position(|job| job.id == id)
+            .map(|position| position + running);
+    }
+
+    /// Cancels a job, whether it is still waiting or already running.
+    pub fn cancel(&self, id: JobId) -> bool {
+        let registry = self.registry.0.lock().unwrap();
+        match registry.cancel_flags.get(&id) {
+            Some(cancelled) => {
+                cancelled.store(true, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+fn run_jobs(registry: Arc<(Mutex<JobRegistry>, Condvar)>, mut context: WorkerContext) {
+    let (registry, job_queued) = &*registry;
+    loop {
+        let job = {
+            let mut registry = registry.lock().unwrap();
+            loop {
+                if let Some((job, priority)) = registry.next_job() {
+                    registry.running = Some((job.id, priority));
+                    break job;
+                }
+                registry = job_queued.wait(registry).unwrap();
+            }
+        };
+
+        let running_job = RunningJob {
+            id: job.id,
+            cancelled: job.cancelled,
+        };
+        if running_job.is_cancelled() {
+            println!("Job {} cancelled before starting", job.id);
+        } else if let Err(message) = (job.run)(&mut context, &running_job) {
+            println!("Job {} panicked: {}", job.id, message);
+        }
+
+        let mut registry = registry.lock().unwrap();
+        registry.running = None;
+        registry.cancel_flags.remove(&job.id);
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        return message.to_string();
+    }
+    if let Some(message) = payload.downcast_ref::<String>() {
+        return message.clone();
+    }
+    return "unknown panic".to_string();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use tauri::async_runtime::block_on;
+
+    use super::*;
+
+    /// Submits a job that holds the worker until the returned sender is
+    /// dropped, and waits until it runs.
+    fn hold_worker(worker: &InferenceWorker) -> (JobHandle<()>, mpsc::Sender<()>) {
+        let (started_sender, started) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let job = worker.submit(move |_, _| {
+            started_sender.send(()).unwrap();
+            let _ = released.recv();
+        });
+        started.recv().unwrap();
+        return (job, release);
+    }
+
+    /// Submits a job that adds its name to the log.
+    fn log_job(
+        worker: &InferenceWorker,
+        priority: JobPriority,
+        log: &Arc<Mutex<Vec<&'static str>>>,
+        name: &'static str,
+    ) -> JobHandle<()> {
+        let log = Arc::clone(log);
+        return worker.submit_with_priority(priority, move |_, _| log.lock().unwrap().push(name));
+    }
+
+    #[test]
+    fn runs_normal_jobs_before_low_ones() {
+        let worker = InferenceWorker::start(None);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (held, release) = hold_worker(&worker);
+        let jobs = vec![
+            log_job(&worker, JobPriority::Low, &log, "first low"),
+            log_job(&worker, JobPriority::Normal, &log, "first normal"),
+            log_job(&worker, JobPriority::Low, &log, "second low"),
+            log_job(&worker, JobPriority::Normal, &log, "second normal"),
+        ];
+        drop(release);
+        block_on(held.wait()).unwrap();
+        for job in jobs {
+            block_on(job.wait()).unwrap();
+        }
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["first normal", "second normal", "first low", "second low"]
+        );
+    }
+
+    #[test]
+    fn reports_queue_positions() {
+        let worker = InferenceWorker::start(None);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (held, release) = hold_worker(&worker);
+        let low = log_job(&worker, JobPriority::Low, &log, "low");
+        let normal = log_job(&worker, JobPriority::Normal, &log, "normal");
+
+        assert_eq!(worker.queue_position(held.id), Some(0));
+        assert_eq!(worker.queue_position(normal.id), Some(1));
+        assert_eq!(worker.queue_position(low.id), Some(2));
+        assert_eq!(worker.queue_position(1000), None);
+
+        drop(release);
+        let (held_id, normal_id) = (held.id, normal.id);
+        block_on(held.wait()).unwrap();
+        block_on(normal.wait()).unwrap();
+        block_on(low.wait()).unwrap();
+        // Finished jobs have no position
+        assert_eq!(worker.queue_position(held_id), None);
+        assert_eq!(worker.queue_position(normal_id), None);
+    }
+
+    #[test]
+    fn normal_jobs_cancel_a_running_low_job() {
+        let worker = InferenceWorker::start(None);
+        let (started_sender, started) = mpsc::channel();
+        let low = worker.submit_with_priority(JobPriority::Low, move |_, running_job| {
+            started_sender.send(()).unwrap();
+            while !running_job.is_cancelled() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            return "gave way";
+        });
+        started.recv().unwrap();
+
+        let normal = worker.submit(|_, running_job| running_job.is_cancelled());
+        assert_eq!(block_on(low.wait()).unwrap(), "gave way");
+        assert!(!block_on(normal.wait()).unwrap());
+    }
+
+    #[test]
+    fn cancels_waiting_and_running_jobs() {
+        let worker = InferenceWorker::start(None);
+        let (started_sender, started) = mpsc::channel();
+        let running = worker.submit(move |_, running_job| {
+            started_sender.send(()).unwrap();
+            while !running_job.is_cancelled() {
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+        started.recv().unwrap();
+        let ran = Arc::new(AtomicBool::new(false));
+        let job_ran = Arc::clone(&ran);
+        let waiting = worker.submit(move |_, _| job_ran.store(true, Ordering::SeqCst));
+
+        assert!(worker.cancel(waiting.id));
+        assert!(worker.cancel(running.id));
+        assert!(!worker.cancel(1000));
+        let running_id = running.id;
+        block_on(running.wait()).unwrap();
+        assert!(matches!(block_on(waiting.wait()), Err(JobError::Cancelled)));
+        assert!(!ran.load(Ordering::SeqCst));
+        assert!(!worker.cancel(running_id));
+    }
+
+    #[test]
+    fn keeps_running_after_a_job_panics() {
+        let worker = InferenceWorker::start(None);
+        let panicking = worker.submit(|_, _| -> u32 { panic!("model failed") });
+        match block_on(panicking.wait()) {
+            Err(JobError::Panicked(message)) => assert_eq!(message, "model failed"),
+            _ => panic!("the job should have panicked"),
+        }
+
+        let next = worker.submit(|context, _| context.model.is_none());
+        assert!(block_on(next.wait()).unwrap());
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::HashSet;
+
+use serde_json::{Map, Value};
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Unsupported schema at {path}: {message}")]
+    Unsupported { path: String, message: String },
+    #[error("The answer doesn't match the schema at {path}: {message}")]
+    Mismatch { path: String, message: String },
+}
+
+/// Rules shared by every grammar generated from a schema, they also make up
+/// the grammar for any JSON value.
+const JSON_RULES: &str = r#"
+value   ::= object | array | string | number | ("true" | "false" | "null") ws
+object  ::= "{" ws ( string ":" ws value ( "," ws string ":" ws value )* )? "}" ws
+array   ::= "[" ws ( value ( "," ws value )* )? "]" ws
+string  ::= "\"" ( [^"\\\x00-\x1f] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] ) )* "\"" ws
+number  ::= "-"? ( "0" | [1-9] [0-9]* ) ( "." [0-9]+ )? ( [eE] [-+]? [0-9]+ )? ws
+integer ::= "-"? ( "0" | [1-9] [0-9]* ) ws
+boolean ::= ( "true" | "false" ) ws
+null    ::= "null" ws
+ws      ::= ( [ \t\n] ws )?
+"#;
+
+/// Grammar that accepts any JSON object.
+pub fn any_object_grammar() -> String {
+    return format!("root ::= object\n{}", JSON_RULES);
+}
+
+/// Converts a JSON Schema to a GBNF grammar for [`crate::grammar::Grammar`].
+///
+/// Supports `type` (also as a list), `properties`, `items`, `enum`, `const`,
+/// `anyOf` and `oneOf`. Every property of an object is generated, in
+/// alphabetical order, which is always valid for the schema.
+pub fn to_grammar(schema: &Value) -> Result<String> {
+    let mut converter = Converter {
+        rules: Vec::new(),
+        names: HashSet::new(),
+    };
+    converter.add_rule("root", schema, "$")?;
+
+    let mut grammar = String::new();
+    for (name, definition) in converter.rules {
+        grammar.push_str(&format!("{} ::= {}\n", name, definition));
+    }
+    grammar.push_str(JSON_RULES);
+    return Ok(grammar);
+}
+
+struct Converter {
+    rules: Vec<(String, String)>,
+    /// Names already taken by a rule
+    names: HashSet<String>,
+}
+
+impl Converter {
+    fn add_rule(&mut self, name: &str, schema: &Value, path: &str) -> Result<String> {
+        // Taken before the definition, whose rules are named after it
+        let name = self.unique_name(name);
+        let definition = self.definition(&name, schema, path)?;
+        self.rules.push((name.clone(), definition));
+        return Ok(name);
+    }
+
+    /// Different properties can map to the same rule name, `a_b` and `a.b`
+    /// for example, so names already taken get a number.
+    fn unique_name(&mut self, name: &str) -> String {
+        let mut unique_name = name.to_string();
+        let mut number = 2;
+        while !self.names.insert(unique_name.clone()) {
+            unique_name = format!("{}-{}", name, number);
+            number += 1;
+        }
+        return unique_name;
+    }
+
+    fn definition(&mut self, name: &str, schema: &Value, path: &str) -> Result<String> {
+        let schema = match schema {
+            Value::Object(schema) => schema,
+            Value::Bool(true) => return Ok("value".to_string()),
+            _ => return Err(unsupported(path, "a schema must be an object")),
+        };
+
+        if let Some(value) = schema.get("const") {
+            return Ok(literal(value));
+        }
+        if let Some(values) = schema.get("enum") {
+            let values = values
+                .as_array()
+                .ok_or_else(|| unsupported(path, "enum must be a list"))?;
+            return Ok(alternatives(values.iter().map(literal).collect()));
+        }
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(keyword) {
+                let schemas = schemas
+                    .as_array()
+                    .ok_or_else(|| unsupported(path, &format!("{} must be a list", keyword)))?;
+                let mut options = Vec::new();
+                for (index, option) in schemas.iter().enumerate() {
+                    options.push(self.add_rule(
+                        &format!("{}-{}", name, index),
+                        option,
+                        &format!("{}/{}/{}", path, keyword, index),
+                    )?);
+                }
+                return Ok(alternatives(options));
+            }
+        }
+        if schema.contains_key("$ref") {
+            return Err(unsupported(path, "$ref is not supported"));
+        }
+
+        match schema.get("type") {
+            None => Ok("value".to_string()),
+            Some(Value::String(schema_type)) => self.typed(name, schema, schema_type, path),
+            Some(Value::Array(schema_types)) => {
+                let mut options = Vec::new();
+                for schema_type in schema_types {
+                    let schema_type = schema_type
+                        .as_str()
+                        .ok_or_else(|| unsupported(path, "type must be a string"))?;
+                    options.push(self.typed(
+                        &format!("{}-{}", name, schema_type),
+                        schema,
+                        schema_type,
+                        path,
+                    )?);
+                }
+                Ok(alternatives(options))
+            }
+            Some(_) => Err(unsupported(path, "type must be a string or a list")),
+        }
+    }
+
+    fn typed(
+        &mut self,
+        name: &str,
+        schema: &Map<String, Value>,
+        schema_type: &str,
+        path: &str,
+    ) -> Result<String> {
+        match schema_type {
+            "string" | "number" | "integer" | "boolean" | "null" => Ok(schema_type.to_string()),
+            "array" => match schema.get("items") {
+                Some(items) => {
+                    let item = self.add_rule(
+                        &format!("{}-item", name),
+                        items,
+                        &format!("{}/items", path),
+                    )?;
+                    Ok(format!(
+                        r#""[" ws ( {item} ( "," ws {item} )* )? "]" ws"#,
+                        item = item
+                    ))
+                }
+                None => Ok("array".to_string()),
+            },
+            "object" => match schema.get("properties").and_then(Value::as_object) {
+                Some(properties) if !properties.is_empty() => {
+                    let mut members = Vec::new();
+                    for (property, property_schema) in properties {
+                        let rule = self.add_rule(
+                            &format!("{}-{}", name, rule_name(property)),
+                            property_schema,
+                            &format!("{}/properties/{}", path, property),
+                        )?;
+                        members.push(format!(
+                            r#"{} ws ":" ws {}"#,
+                            literal_text(&Value::String(property.clone()).to_string()),
+                            rule
+                        ));
+                    }
+                    Ok(format!(
+                        r#""{{" ws {} "}}" ws"#,
+                        members.join(r#" "," ws "#)
+                    ))
+                }
+                _ => Ok("object".to_string()),
+            },
+            _ => Err(unsupported(path, &format!("unknown type {}", schema_type))),
+        }
+    }
+}
+
+/// Checks a value against the parts of the schema [`to_grammar`] supports.
+pub fn validate(schema: &Value, value: &Value) -> Result<()> {
+    return validate_at(schema, value, "$");
+}
+
+fn validate_at(schema: &Value, value: &Value, path: &str) -> Result<()> {
+    let schema = match schema {
+        Value::Object(schema) => schema,
+        _ => return Ok(()),
+    };
+
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            return Err(mismatch(path, &format!("expected {}", expected)));
+        }
+    }
+    if let Some(Value::Array(values)) = schema.get("enum") {
+        if !values.contains(value) {
+            return Err(mismatch(path, "not one of the allowed values"));
+        }
+    }
+    for keyword in ["anyOf", "oneOf"] {
+        if let Some(Value::Array(schemas)) = schema.get(keyword) {
+            if !schemas
+                .iter()
+                .any(|option| validate_at(option, value, path).is_ok())
+            {
+                return Err(mismatch(path, &format!("matches none of {}", keyword)));
+            }
+        }
+    }
+
+    match schema.get("type") {
+        Some(Value::String(schema_type)) => check_type(schema_type, value, path)?,
+        Some(Value::Array(schema_types)) => {
+            let matches_any = schema_types.iter().any(|schema_type| {
+                schema_type.as_str().map_or(false, |schema_type| {
+                    check_type(schema_type, value, path).is_ok()
+                })
+            });
+            if !matches_any {
+                return Err(mismatch(path, "has none of the allowed types"));
+            }
+        }
+        _ => {}
+    }
+
+    match value {
+        Value::Object(object) => {
+            if let Some(Value::Array(required)) = schema.get("required") {
+                for property in required.iter().filter_map(Value::as_str) {
+                    if !object.contains_key(property) {
+                        return Err(mismatch(path, &format!("missing property {}", property)));
+                    }
+                }
+            }
+            if let Some(Value::Object(properties)) = schema.get("properties") {
+                for (property, property_value) in object {
+                    if let Some(property_schema) = properties.get(property) {
+                        validate_at(
+                            property_schema,
+                            property_value,
+                            &format!("{}.{}", path, property),
+                        )?;
+                    }
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(items_schema) = schema.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    validate_at(items_schema, item, &format!("{}[{}]", path, index))?;
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn check_type(schema_type: &str, value: &Value, path: &str) -> Result<()> {
+    let matches = match schema_type {
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => true,
+    };
+    if !matches {
+        return Err(mismatch(path, &format!("expected a {}", schema_type)));
+    }
+    Ok(())
+}
+
+/// Grammar literal for a JSON value, followed by optional whitespace.
+fn literal(value: &Value) -> String {
+    return format!("{} ws", literal_text(&value.to_string()));
+}
+
+fn literal_text(text: &str) -> String {
+    return format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""));
+}
+
+fn alternatives(options: Vec<String>) -> String {
+    return format!("( {} )", options.join(" | "));
+}
+
+fn rule_name(property: &str) -> String {
+    return property
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+}
+
+fn unsupported(path: &str, message: &str) -> Error {
+    return Error::Unsupported {
+        path: path.to_string(),
+        message: message.to_string(),
+    };
+}
+
+fn mismatch(path: &str, message: &str) -> Error {
+    return Error::Mismatch {
+        path: path.to_string(),
+        message: message.to_string(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::grammar::{Grammar, GrammarMatcher};
+
+    /// Whether the grammar for the schema matches the whole text.
+    fn matches(schema: &Value, text: &str) -> bool {
+        let grammar = Arc::new(Grammar::parse(&to_grammar(schema).unwrap()).unwrap());
+        let mut matcher = GrammarMatcher::new(grammar);
+        if !matcher.accepts(text) {
+            return false;
+        }
+        matcher.advance(text);
+        return matcher.is_complete();
+    }
+
+    #[test]
+    fn converts_objects_arrays_and_enums() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "tags": { "type": "array", "items": { "enum": ["a", "b"] } },
+                "age": { "type": ["integer", "null"] }
+            }
+        });
+        assert!(matches(
+            &schema,
+            r#"{"age": 3, "name": "x", "tags": ["a", "b"]}"#
+        ));
+        assert!(matches(&schema, r#"{"age": null, "name": "", "tags": []}"#));
+        assert!(!matches(
+            &schema,
+            r#"{"age": 3.5, "name": "x", "tags": []}"#
+        ));
+        assert!(!matches(
+            &schema,
+            r#"{"age": 3, "name": "x", "tags": ["c"]}"#
+        ));
+    }
+
+    #[test]
+    fn converts_any_of_and_const() {
+        let schema = json!({ "anyOf": [{ "const": "none" }, { "type": "number" }] });
+        assert!(matches(&schema, r#""none""#));
+        assert!(matches(&schema, "-1.5e3"));
+        assert!(!matches(&schema, r#""some""#));
+    }
+
+    #[test]
+    fn gives_properties_with_the_same_rule_name_their_own_rules() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "a_b": { "type": "string" },
+                "a.b": { "type": "integer" },
+                "a": {
+                    "type": "object",
+                    "properties": { "b": { "type": "boolean" } }
+                }
+            }
+        });
+        assert!(matches(
+            &schema,
+            r#"{"a": {"b": true}, "a.b": 1, "a_b": "x"}"#
+        ));
+        assert!(!matches(
+            &schema,
+            r#"{"a": {"b": true}, "a.b": "x", "a_b": 1}"#
+        ));
+    }
+
+    #[test]
+    fn rejects_unsupported_schemas() {
+        assert!(matches!(
+            to_grammar(&json!({ "$ref": "#/definitions/a" })),
+            Err(Error::Unsupported { .. })
+        ));
+        assert!(matches!(
+            to_grammar(&json!({ "type": "date" })),
+            Err(Error::Unsupported { .. })
+        ));
+    }
+
+    #[test]
+    fn validates_values() {
+        let schema = json!({
+            "type": "object",
+            "required": ["name"],
+            "properties": {
+                "name": { "type": "string" },
+                "scores": { "type": "array", "items": { "type": "number" } }
+            }
+        });
+        assert!(validate(&schema, &json!({ "name": "x", "scores": [1, 2.5] })).is_ok());
+        assert!(matches!(
+            validate(&schema, &json!({ "scores": [] })),
+            Err(Error::Mismatch { path, .. }) if path == "$"
+        ));
+        assert!(matches!(
+            validate(&schema, &json!({ "name": "x", "scores": [1, "2"] })),
+            Err(Error::Mismatch { path, .. }) if path == "$.scores[1]"
+        ));
+    }
+}
@@ -5,6 +5,9 @@ use llm::InferenceRequest;
 use llm::InferenceStats;
 use llm::LoadError;
 use llm::Prompt;
+use llm::TokenId;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use rust_decimal::prelude::*;
 use rust_decimal::Decimal;
 use std::convert::Infallible;
@@ -17,8 +20,25 @@ use std::sync::Mutex;
 use std::vec;
 use tauri::Manager;
 
+use crate::completion::{CommandError, Completion, StopReason};
+use crate::conversation::{
+    Conversation, ConversationId, Message, MessageId, MessageRole, PathMessage,
+};
+use crate::conversation_store::ConversationStore;
+use crate::grammar::Grammar;
+use crate::inference_stats::{self, CompletionStats};
+use crate::inference_worker::{
+    InferenceWorker, JobHandle, JobId, JobPriority, RunningJob, WorkerContext,
+};
 use crate::localstore::CurrentLanguageModel;
-use crate::{configs, downloader, localstore, AppState};
+use crate::parameters::InferenceParameters;
+use crate::profiles::{self, ParameterSource, ProfileUpdate};
+use crate::prompt_template::ChatTemplate;
+use crate::samplers::{GrammarSampler, ProbabilitySampler, SampledToken};
+use crate::stop_sequences::StopSequenceMatcher;
+use crate::{
+    configs, conversation_store, downloader, json_schema, localstore, samplers, snapshots,
+};
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct LanguageModel {
@@ -36,21 +56,23 @@ pub struct LanguageModelInfo {
     url: String,
     image: String,
     prompt_template: String,
+    chat_template: Option<ChatTemplate>,
     size: String,
 }
 
 pub struct ChatState {
-    pub messages: Mutex<Vec<Message>>,
-}
-pub struct Message {
-    text: String,
-    role: MessageRole,
+    pub conversation: Mutex<Conversation>,
+    pub session: Mutex<Option<ChatSession>>,
 }
 
-#[derive(Debug)]
-pub enum MessageRole {
-    Human,
-    AI,
+/// Inference session kept alive between chat turns, so only the new part of
+/// the conversation has to be fed to the model.
+pub struct ChatSession {
+    session: llm::InferenceSession,
+    /// Identifies the model, template and parameters the session was built with
+    key: String,
+    /// Text already evaluated by the session
+    transcript: String,
 }
 
 #[derive(serde::Serialize)]
@@ -61,7 +83,17 @@ pub struct GetLanguageModelsResponse {
 #[tauri::command]
 pub fn get_prompt_template(app_handle: tauri::AppHandle) -> String {
     println!("Command: get_prompt");
-    return localstore::get_prompt_template(app_handle);
+    return profiles::get_effective_profile(&app_handle)
+        .prompt_template
+        .value;
+}
+
+#[tauri::command]
+pub fn get_chat_template(app_handle: tauri::AppHandle) -> ChatTemplate {
+    println!("Command: get_chat_template");
+    return profiles::get_effective_profile(&app_handle)
+        .chat_template
+        .value;
 }
 
 #[tauri::command]
@@ -112,6 +144,7 @@ pub fn get_language_models(app_handle: tauri::AppHandle) -> GetLanguageModelsRes
             url: config_model.url.clone(),
             image: config_model.image.clone(),
             prompt_template: config_model.prompt_template.clone(),
+            chat_template: config_model.chat_template.clone(),
             size: config_model.size.clone(),
         });
 
@@ -138,29 +171,77 @@ pub fn get_language_models(app_handle: tauri::AppHandle) -> GetLanguageModelsRes
 }
 
 #[tauri::command]
-pub fn set_current_model(
-    model_filename: &str,
-    model_name: &str,
-    model_arquitecture: &str,
+pub async fn set_current_model(
+    model_filename: String,
+    model_name: String,
+    model_arquitecture: String,
     app_handle: tauri::AppHandle,
 ) -> Result<(), String> {
     println!("Command: set_current_model, filename:{}", model_filename);
+    return swap_model(
+        model_filename,
+        model_name,
+        model_arquitecture,
+        false,
+        app_handle,
+    )
+    .await;
+}
+
+/// Loads a model and makes it the current one. The chat is cleared, unless
+/// `keep_chat` is set because the same model is loaded again with other
+/// parameters.
+async fn swap_model(
+    model_filename: String,
+    model_name: String,
+    model_arquitecture: String,
+    keep_chat: bool,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
     let models_path_str = localstore::get_models_folder(app_handle.clone()).unwrap();
     let mut models_path = PathBuf::from(&models_path_str);
     models_path.push(&model_filename);
-    match load_model(&models_path, model_arquitecture) {
+
+    // Loading takes a while, so it happens outside the worker and only the
+    // swap waits for the jobs queued before it.
+    let load_path = models_path.clone();
+    let load_arquitecture = model_arquitecture.clone();
+    let parameters = profiles::get_effective_profile_for(&app_handle, &model_filename)
+        .parameters
+        .value;
+    let loaded = tauri::async_runtime::spawn_blocking(move || {
+        load_model(&load_path, &load_arquitecture, &parameters)
+    })
+    .await
+    .map_err(|err| err.to_string())?;
+
+    match loaded {
         Ok(model) => {
-            let app_state = app_handle.state::<AppState>();
-            app_state.model.lock().unwrap().replace(model);
+            let worker = app_handle.state::<InferenceWorker>();
+            let swap_app_handle = app_handle.clone();
+            worker
+                .submit(move |context, _| {
+                    context.model.replace(model);
+                    let chat_state = swap_app_handle.state::<ChatState>();
+                    if keep_chat {
+                        // The session was built on the model that was replaced
+                        chat_state.session.lock().unwrap().take();
+                    } else {
+                        // The history was written for the previous model
+                        clear_chat(&chat_state);
+                    }
+                })
+                .wait()
+                .await
+                .map_err(|err| err.to_string())?;
 
-            let app_handle_cloned = Arc::new(Mutex::new(app_handle));
             localstore::save_current_model(
-                Arc::clone(&app_handle_cloned).lock().unwrap().app_handle(),
+                app_handle,
                 CurrentLanguageModel {
-                    name: model_name.to_string(),
-                    filename: model_filename.to_string(),
+                    name: model_name,
+                    filename: model_filename,
                     path: models_path.to_string_lossy().to_string(),
-                    arquitecture: model_arquitecture.to_string(),
+                    arquitecture: model_arquitecture,
                 },
             )?;
 
@@ -179,122 +260,949 @@ pub fn get_active_model(app_handle: tauri::AppHandle) -> Option<CurrentLanguageM
 
 #[derive(Clone, serde::Serialize)]
 struct Payload {
+    request_id: JobId,
+    message: String,
+    /// Only sent when the request asked for token probabilities
+    #[serde(skip_serializing_if = "Option::is_none")]
+    probabilities: Option<Vec<TokenProbability>>,
+}
+
+/// Log-probability of a generated token and of the tokens the model found
+/// most likely in its place.
+#[derive(Clone, serde::Serialize)]
+pub struct TokenProbability {
+    token: String,
+    logprob: f32,
+    alternatives: Vec<TokenAlternative>,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct TokenAlternative {
+    token: String,
+    logprob: f32,
+}
+
+/// Most alternatives a request can ask for per token
+const MAX_TOP_LOGPROBS: usize = 20;
+
+/// What [`start_inference`] generated and why it stopped.
+struct InferenceOutput {
+    stats: InferenceStats,
+    generated_text: String,
+    /// The part of the generated text the session still holds, all of it
+    /// unless a stop sequence was rewound
+    session_text: String,
+    /// Whether the whole prompt was read, a request cancelled while it was
+    /// being read has no answer
+    prompt_fed: bool,
+    stop_reason: StopReason,
+}
+
+/// Asked after the first exchange to title a conversation
+const TITLE_REQUEST: &str =
+    "Write a title of at most six words for our conversation. Reply with the title only.";
+/// Most characters of each message shown to the model when asking for a title
+const TITLE_EXCERPT_LENGTH: usize = 1000;
+const TITLE_TOKENS: usize = 24;
+const TITLE_MAX_LENGTH: usize = 60;
+
+/// Optional behaviour of [`start_inference`].
+#[derive(Default)]
+struct InferenceOptions {
+    /// Only generate text this grammar allows
+    grammar: Option<Arc<Grammar>>,
+    /// Report token probabilities with this many alternatives
+    top_logprobs: Option<usize>,
+    /// Generate at most this many tokens instead of the configured maximum
+    maximum_token_count: Option<usize>,
+}
+
+#[derive(Clone, serde::Serialize)]
+struct HistoryTrimmedPayload {
+    request_id: JobId,
+    trimmed_turns: usize,
+}
+
+#[derive(Clone, serde::Serialize)]
+struct FinishedPayload {
+    request_id: JobId,
+    completion: Option<Completion>,
+    error: Option<CommandError>,
+}
+
+/// Sent as soon as a request is queued, so the window learns its id before
+/// any token arrives. `client_id` is whatever the window passed along.
+#[derive(Clone, serde::Serialize)]
+struct SubmittedPayload {
+    request_id: JobId,
+    client_id: Option<String>,
+}
+
+#[derive(Clone, serde::Serialize)]
+struct QueuedPayload {
+    request_id: JobId,
+    position: usize,
+}
+
+/// Runs a chat turn and returns the completed answer. Once queued,
+/// `inference_submitted` tells the request id, which also tags the streamed
+/// `new_token` events and the final `inference_finished` event.
+///
+/// With `top_logprobs`, every `new_token` event also carries the probability
+/// of the streamed tokens and that many alternatives for each.
+#[tauri::command]
+pub async fn chat(
     message: String,
+    top_logprobs: Option<usize>,
+    client_id: Option<String>,
+    app_handle: tauri::AppHandle,
+    window: tauri::Window,
+) -> Result<Completion, CommandError> {
+    return submit_chat(
+        ChatAction::Send(message),
+        top_logprobs,
+        client_id,
+        app_handle,
+        window,
+    )
+    .await;
+}
+
+/// Answers the last human message again, as an alternative to the last reply. See
+/// [`chat`] for how the answer is delivered.
+#[tauri::command]
+pub async fn regenerate_message(
+    message_id: MessageId,
+    top_logprobs: Option<usize>,
+    client_id: Option<String>,
+    app_handle: tauri::AppHandle,
+    window: tauri::Window,
+) -> Result<Completion, CommandError> {
+    return submit_chat(
+        ChatAction::Regenerate(message_id),
+        top_logprobs,
+        client_id,
+        app_handle,
+        window,
+    )
+    .await;
+}
+
+/// Continues the last reply from where it stopped, for example when it hit
+/// the max tokens. The answer of the completion is only the new text.
+#[tauri::command]
+pub async fn continue_message(
+    message_id: MessageId,
+    top_logprobs: Option<usize>,
+    client_id: Option<String>,
+    app_handle: tauri::AppHandle,
+    window: tauri::Window,
+) -> Result<Completion, CommandError> {
+    return submit_chat(
+        ChatAction::Continue(message_id),
+        top_logprobs,
+        client_id,
+        app_handle,
+        window,
+    )
+    .await;
+}
+
+/// Sends an edited version of a human message, as an alternative to it, and
+/// answers it. The original message and its replies are kept in their branch.
+#[tauri::command]
+pub async fn edit_message(
+    message_id: MessageId,
+    text: String,
+    top_logprobs: Option<usize>,
+    client_id: Option<String>,
+    app_handle: tauri::AppHandle,
+    window: tauri::Window,
+) -> Result<Completion, CommandError> {
+    return submit_chat(
+        ChatAction::Edit(message_id, text),
+        top_logprobs,
+        client_id,
+        app_handle,
+        window,
+    )
+    .await;
+}
+
+async fn submit_chat(
+    action: ChatAction,
+    top_logprobs: Option<usize>,
+    client_id: Option<String>,
+    app_handle: tauri::AppHandle,
+    window: tauri::Window,
+) -> Result<Completion, CommandError> {
+    let worker = app_handle.state::<InferenceWorker>();
+    let job_app_handle = app_handle.clone();
+    let job_window = window.clone();
+    let options = InferenceOptions {
+        top_logprobs: top_logprobs.map(|count| count.min(MAX_TOP_LOGPROBS)),
+        ..Default::default()
+    };
+    let job = worker.submit(move |context, running_job| {
+        run_chat(
+            action,
+            options,
+            job_app_handle,
+            job_window,
+            context,
+            running_job,
+        )
+    });
+    let position = worker.queue_position(job.id);
+    return wait_for_job(job, position, client_id, window).await;
+}
+
+/// Tells the window the request id and where the job is in the queue, then
+/// waits for it and emits `inference_finished`.
+async fn wait_for_job(
+    job: JobHandle<Result<Completion, CommandError>>,
+    position: Option<usize>,
+    client_id: Option<String>,
+    window: tauri::Window,
+) -> Result<Completion, CommandError> {
+    let request_id = job.id;
+    window
+        .emit(
+            "inference_submitted",
+            SubmittedPayload {
+                request_id,
+                client_id,
+            },
+        )
+        .unwrap();
+    if let Some(position) = position {
+        if position > 0 {
+            println!("Request {} queued at position {}", request_id, position);
+            window
+                .emit(
+                    "inference_queued",
+                    QueuedPayload {
+                        request_id,
+                        position,
+                    },
+                )
+                .unwrap();
+        }
+    }
+
+    let result = job.wait().await.unwrap_or_else(|err| Err(err.into()));
+    window
+        .emit(
+            "inference_finished",
+            FinishedPayload {
+                request_id,
+                completion: result.as_ref().ok().cloned(),
+                error: result.as_ref().err().cloned(),
+            },
+        )
+        .unwrap();
+    return result;
 }
 
 #[tauri::command]
-pub async fn chat(message: String, app_handle: tauri::AppHandle, window: tauri::Window) -> String {
-    let app_state = app_handle.state::<AppState>();
+pub fn get_queue_position(request_id: JobId, app_handle: tauri::AppHandle) -> Option<usize> {
+    let worker = app_handle.state::<InferenceWorker>();
+    return worker.queue_position(request_id);
+}
+
+/// What a chat request does to the history before the model answers.
+enum ChatAction {
+    /// Adds a human message
+    Send(String),
+    /// Adds an alternative to the last reply, which must have this id
+    Regenerate(MessageId),
+    /// Continues the last reply, which must have this id
+    Continue(MessageId),
+    /// Adds an alternative to a human message
+    Edit(MessageId, String),
+}
+
+fn apply_chat_action(
+    conversation: &mut Conversation,
+    action: ChatAction,
+) -> Result<(), CommandError> {
+    match action {
+        ChatAction::Send(text) => {
+            conversation.push(MessageRole::Human, text);
+        }
+        ChatAction::Regenerate(message_id) => {
+            check_is_last_reply(conversation, message_id)?;
+            conversation.branch_from(message_id);
+        }
+        ChatAction::Continue(message_id) => {
+            check_is_last_reply(conversation, message_id)?;
+        }
+        ChatAction::Edit(message_id, text) => {
+            match conversation.find(message_id) {
+                Some(message) if message.role == MessageRole::Human => {}
+                _ => {
+                    return Err(CommandError::InvalidMessage(
+                        "Only messages sent by you can be edited".to_string(),
+                    ))
+                }
+            }
+            conversation.branch_from(message_id);
+            conversation.push(MessageRole::Human, text);
+        }
+    }
+    Ok(())
+}
+
+fn check_is_last_reply(
+    conversation: &Conversation,
+    message_id: MessageId,
+) -> Result<(), CommandError> {
+    match conversation.last() {
+        Some(last) if last.id == message_id && last.role == MessageRole::AI => Ok(()),
+        _ => Err(CommandError::InvalidMessage(
+            "Only the last reply can be regenerated or continued".to_string(),
+        )),
+    }
+}
+
+fn run_chat(
+    action: ChatAction,
+    options: InferenceOptions,
+    app_handle: tauri::AppHandle,
+    window: tauri::Window,
+    context: &WorkerContext,
+    running_job: &RunningJob,
+) -> Result<Completion, CommandError> {
     let chat_state = app_handle.state::<ChatState>();
 
-    match app_state.inner().model.lock().unwrap().as_ref() {
+    match context.model.as_ref() {
         Some(model) => {
-            let mut messages = chat_state.inner().messages.lock().unwrap();
-            messages.push(Message {
-                text: message.clone(),
-                role: MessageRole::Human,
-            });
+            // The job works on a copy and writes it back with the answer, so
+            // the history can be read while the model generates and only
+            // changes when an answer is saved
+            let mut conversation = chat_state.inner().conversation.lock().unwrap().clone();
+            let continuing = matches!(action, ChatAction::Continue(_));
+            apply_chat_action(&mut conversation, action)?;
 
-            // TODO: limit the number of messages to the last X,
-            // so the context is not too big
-            let prompt = messages
-                .iter()
-                .map(|message| match message.role {
-                    MessageRole::Human => {
-                        let prompt_template = localstore::get_prompt_template(app_handle.clone());
-                        let mut prompt = message.text.clone();
-                        if prompt_template.contains("[[message]]") {
-                            prompt = prompt_template.replace("[[message]]", &prompt);
-                        }
-                        prompt
-                    }
-                    MessageRole::AI => message.text.clone(),
-                })
-                .collect::<Vec<String>>()
-                .join(" ");
-            println!("Prompt: {}", prompt);
-
-            let mut answer: String = "".to_string();
-            let res = start_inference(&app_handle, model, "this is".to_string(), |token| {
-                println!("{token}");
-                answer.push_str(&token);
+            let profile = profiles::get_effective_profile(&app_handle);
+            let chat_template = profile.chat_template.value;
+            let parameters = profile.parameters.value;
+            let messages = conversation.selected_path();
+            let (first_message, trimmed_turns) = fit_history_in_context(
+                model.as_ref(),
+                &chat_template,
+                &messages,
+                parameters.maximum_token_count,
+            );
+            if trimmed_turns > 0 {
+                println!("History trimmed: {} turns left out", trimmed_turns);
                 window
                     .emit(
-                        "new_token",
-                        Payload {
-                            message: token.to_string(),
+                        "history_trimmed",
+                        HistoryTrimmedPayload {
+                            request_id: running_job.id,
+                            trimmed_turns,
                         },
                     )
                     .unwrap();
-                Ok(InferenceFeedback::Continue)
-            });
-            messages.push(Message {
-                text: answer.clone(),
-                role: MessageRole::AI,
-            });
+            }
+            let kept_messages = &messages[first_message..];
+            let prompt = if continuing {
+                chat_template.render_continuation(kept_messages)
+            } else {
+                chat_template.render(kept_messages)
+            };
+            println!("Prompt: {}", prompt);
+
+            // Taken out while generating, it's only put back with an answer
+            let key = session_key(&app_handle);
+            let previous_session = chat_state.inner().session.lock().unwrap().take();
+            let (mut cached, new_prompt_text) = match previous_session {
+                Some(cached) if cached.key == key && prompt.starts_with(&cached.transcript) => {
+                    println!("Reusing chat session");
+                    let new_prompt_text = prompt[cached.transcript.len()..].to_string();
+                    (cached, new_prompt_text)
+                }
+                _ => {
+                    println!("Starting new chat session");
+                    let cached = ChatSession {
+                        session: model.start_session(session_config(&parameters)),
+                        key,
+                        transcript: "".to_string(),
+                    };
+                    (cached, prompt.clone())
+                }
+            };
+
+            let mut answer: String = "".to_string();
+            let res = start_inference(
+                &app_handle,
+                model,
+                running_job,
+                &mut cached.session,
+                new_prompt_text,
+                options,
+                |token, probabilities| {
+                    println!("{token}");
+                    answer.push_str(&token);
+                    window
+                        .emit(
+                            "new_token",
+                            Payload {
+                                request_id: running_job.id,
+                                message: token.to_string(),
+                                probabilities,
+                            },
+                        )
+                        .unwrap();
+                    Ok(InferenceFeedback::Continue)
+                },
+            );
 
             match res {
-                Ok(_) => format!("{}", answer),
-                Err(err) => format!("\n{err}"),
+                // The session only holds part of the prompt, it's dropped
+                Ok(output) if !output.prompt_fed => Err(CommandError::Halted),
+                Ok(output) => {
+                    // The answer is only part of the history once the generation
+                    // has finished, either by end of text or because it was halted.
+                    // The next prompt is compared against the text the session
+                    // holds, which has no stop sequence once it's rewound.
+                    cached.transcript = format!("{}{}", prompt, output.session_text);
+                    if continuing {
+                        conversation.append_to_last(&answer);
+                    } else {
+                        conversation.push(MessageRole::AI, answer.clone());
+                    }
+                    conversation_store::save(&app_handle, &mut conversation);
+                    request_title(&app_handle, &conversation);
+                    *chat_state.inner().conversation.lock().unwrap() = conversation;
+                    chat_state.inner().session.lock().unwrap().replace(cached);
+                    Ok(completion(&app_handle, running_job, answer, output))
+                }
+                Err(err) => Err(CommandError::from(err)),
             }
         }
         None => {
             println!("No model loaded");
-            return "Error: No model loaded".to_string();
+            return Err(CommandError::NoModelLoaded);
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn get_chat_history(app_handle: tauri::AppHandle) -> Vec<PathMessage> {
+    let chat_state = app_handle.state::<ChatState>();
+    let conversation = chat_state.inner().conversation.lock().unwrap();
+    return conversation.selected_path_with_siblings();
+}
+
+/// Starts a new conversation. Queued behind the requests already sent, so
+/// they answer in the conversation they were sent to.
+#[tauri::command]
+pub async fn new_chat(app_handle: tauri::AppHandle) -> Result<(), String> {
+    println!("Command: new_chat");
+    let worker = app_handle.state::<InferenceWorker>();
+    let job_app_handle = app_handle.clone();
+    return worker
+        .submit(move |_, _| clear_chat(&job_app_handle.state::<ChatState>()))
+        .wait()
+        .await
+        .map_err(|err| err.to_string());
+}
+
+fn clear_chat(chat_state: &ChatState) {
+    replace_chat(chat_state, Conversation::default());
+}
+
+/// Makes a conversation the current chat. The cached session belongs to the
+/// previous one, so it is dropped.
+pub(crate) fn replace_chat(chat_state: &ChatState, conversation: Conversation) {
+    *chat_state.conversation.lock().unwrap() = conversation;
+    chat_state.session.lock().unwrap().take();
+}
+
+/// Switches to another branch of the conversation, the one through the given
+/// message, and returns the new chat history. Queued like `new_chat`, so a
+/// running generation doesn't write over the switch.
+#[tauri::command]
+pub async fn select_branch(
+    message_id: MessageId,
+    app_handle: tauri::AppHandle,
+) -> Result<Vec<PathMessage>, CommandError> {
+    println!("Command: select_branch, message_id:{}", message_id);
+    let worker = app_handle.state::<InferenceWorker>();
+    let job_app_handle = app_handle.clone();
+    return worker
+        .submit(move |_, _| {
+            let chat_state = job_app_handle.state::<ChatState>();
+            let mut conversation = chat_state.inner().conversation.lock().unwrap();
+            if !conversation.select(message_id) {
+                return Err(CommandError::InvalidMessage(format!(
+                    "Message {} not found",
+                    message_id
+                )));
+            }
+            conversation_store::save(&job_app_handle, &mut conversation);
+            return Ok(conversation.selected_path_with_siblings());
+        })
+        .wait()
+        .await
+        .unwrap_or_else(|err| Err(err.into()));
+}
+
+/// Writes the current chat and its inference session to disk, so the
+/// conversation can be continued without evaluating it again.
+pub fn save_chat_session(app_handle: &tauri::AppHandle) {
+    let current_model = match localstore::get_active_model(app_handle.clone()) {
+        Some(current_model) => current_model,
+        None => return,
+    };
+    let chat_state = app_handle.state::<ChatState>();
+    let conversation = chat_state.inner().conversation.lock().unwrap();
+    let chat_session = chat_state.inner().session.lock().unwrap();
+    match chat_session.as_ref() {
+        Some(cached) => {
+            if let Err(err) = snapshots::save_chat(
+                app_handle,
+                &PathBuf::from(current_model.path),
+                &cached.session,
+                &cached.key,
+                &cached.transcript,
+                &conversation,
+            ) {
+                println!("Error saving chat session: {}", err);
+            }
+        }
+        None => snapshots::delete_chat(app_handle),
+    }
+}
+
+/// Loads the chat saved by [`save_chat_session`], if it was taken with the
+/// model that is loaded now.
+pub fn restore_chat_session(app_handle: &tauri::AppHandle) {
+    let worker = app_handle.state::<InferenceWorker>();
+    let job_app_handle = app_handle.clone();
+    worker.submit(move |context, _| {
+        restore_chat_session_with_model(&job_app_handle, context.model.as_deref())
+    });
+}
+
+fn restore_chat_session_with_model(app_handle: &tauri::AppHandle, model: Option<&dyn llm::Model>) {
+    let current_model = match localstore::get_active_model(app_handle.clone()) {
+        Some(current_model) => current_model,
+        None => return,
+    };
+    let model = match model {
+        Some(model) => model,
+        None => return,
+    };
+
+    match snapshots::load_chat(app_handle, &PathBuf::from(current_model.path), model) {
+        Ok(restored) => {
+            let chat_state = app_handle.state::<ChatState>();
+            *chat_state.inner().conversation.lock().unwrap() = restored.conversation;
+            chat_state
+                .inner()
+                .session
+                .lock()
+                .unwrap()
+                .replace(ChatSession {
+                    session: restored.session,
+                    key: restored.key,
+                    transcript: restored.transcript,
+                });
         }
+        Err(err) => println!("No chat session restored: {}", err),
     }
 }
 
-fn stop_infering(app_handle: &tauri::AppHandle) -> bool {
-    let session_state = app_handle.state::<SessionState>();
-    let should_stop_infering = session_state.inner().should_stop_infering.lock().unwrap();
-    return *should_stop_infering;
+/// Everything a cached chat session depends on. When any of these change the
+/// session can't be continued and has to be built again.
+fn session_key(app_handle: &tauri::AppHandle) -> String {
+    let profile = profiles::get_effective_profile(app_handle);
+    return [
+        localstore::get_current_model_filename(app_handle.clone()),
+        serde_json::to_string(&profile.chat_template.value).unwrap(),
+        serde_json::to_string(&profile.parameters.value).unwrap(),
+    ]
+    .join("\u{0}");
+}
+
+/// Finds the oldest message that can be kept so the rendered prompt plus the
+/// tokens we allow the model to generate fit in the context window.
+///
+/// Whole turns are dropped, starting with the oldest one, and the last human
+/// message is always kept. Returns the index of the first message to keep and
+/// how many turns were left out.
+fn fit_history_in_context(
+    model: &dyn llm::Model,
+    chat_template: &ChatTemplate,
+    messages: &[Message],
+    maximum_token_count: usize,
+) -> (usize, usize) {
+    let last_human_message = messages
+        .iter()
+        .rposition(|message| matches!(message.role, MessageRole::Human))
+        .unwrap_or(0);
+
+    let mut first_message = 0;
+    let mut trimmed_turns = 0;
+    while first_message < last_human_message {
+        let prompt = chat_template.render(&messages[first_message..]);
+        if count_tokens(model, &prompt) + maximum_token_count <= model.context_size() {
+            break;
+        }
+
+        // Skip the human message of the oldest turn and the answers to it
+        first_message += 1;
+        while first_message < last_human_message
+            && !matches!(messages[first_message].role, MessageRole::Human)
+        {
+            first_message += 1;
+        }
+        trimmed_turns += 1;
+    }
+    return (first_message, trimmed_turns);
 }
 
-fn reset_stop_infering(app_handle: &tauri::AppHandle) {
-    let session_state = app_handle.state::<SessionState>();
-    let mut should_stop_infering = session_state.inner().should_stop_infering.lock().unwrap();
-    *should_stop_infering = false;
+fn count_tokens(model: &dyn llm::Model, text: &str) -> usize {
+    match model.vocabulary().tokenize(text, true) {
+        Ok(tokens) => tokens.len(),
+        Err(err) => {
+            // Without a token count, assume the worst case of one token per byte
+            println!("Error tokenizing text: {}", err);
+            text.len()
+        }
+    }
 }
 
+/// Answers a single question, see [`chat`] for how the answer is delivered.
 #[tauri::command]
-pub async fn ask(message: String, app_handle: tauri::AppHandle, window: tauri::Window) -> String {
-    let app_state = app_handle.state::<AppState>();
-    match app_state.inner().model.lock().unwrap().as_ref() {
+pub async fn ask(
+    message: String,
+    top_logprobs: Option<usize>,
+    client_id: Option<String>,
+    app_handle: tauri::AppHandle,
+    window: tauri::Window,
+) -> Result<Completion, CommandError> {
+    let worker = app_handle.state::<InferenceWorker>();
+    let job_app_handle = app_handle.clone();
+    let job_window = window.clone();
+    let options = InferenceOptions {
+        top_logprobs: top_logprobs.map(|count| count.min(MAX_TOP_LOGPROBS)),
+        ..Default::default()
+    };
+    let job = worker.submit(move |context, running_job| {
+        run_ask(
+            message,
+            options,
+            job_app_handle,
+            job_window,
+            context,
+            running_job,
+        )
+    });
+    let position = worker.queue_position(job.id);
+    return wait_for_job(job, position, client_id, window).await;
+}
+
+fn run_ask(
+    message: String,
+    options: InferenceOptions,
+    app_handle: tauri::AppHandle,
+    window: tauri::Window,
+    context: &WorkerContext,
+    running_job: &RunningJob,
+) -> Result<Completion, CommandError> {
+    match context.model.as_ref() {
         Some(model) => {
-            let prompt_template = localstore::get_prompt_template(app_handle.clone());
+            let profile = profiles::get_effective_profile(&app_handle);
+            let prompt_template = profile.prompt_template.value;
             let mut prompt = message;
             if prompt_template.contains("[[message]]") {
                 prompt = prompt_template.replace("[[message]]", &prompt);
             }
             println!("Prompt: {}", prompt);
 
+            let mut session = model.start_session(session_config(&profile.parameters.value));
             let mut answer: String = "".to_string();
-            let res = start_inference(&app_handle, model, prompt, |token| {
-                std::io::stdout().flush().unwrap();
-                println!("{token}");
-                answer.push_str(&token);
-                window
-                    .emit(
-                        "new_token",
-                        Payload {
-                            message: token.to_string(),
-                        },
-                    )
-                    .unwrap();
-                Ok(InferenceFeedback::Continue)
-            });
+            let res = start_inference(
+                &app_handle,
+                model,
+                running_job,
+                &mut session,
+                prompt,
+                options,
+                |token, probabilities| {
+                    std::io::stdout().flush().unwrap();
+                    println!("{token}");
+                    answer.push_str(&token);
+                    window
+                        .emit(
+                            "new_token",
+                            Payload {
+                                request_id: running_job.id,
+                                message: token.to_string(),
+                                probabilities,
+                            },
+                        )
+                        .unwrap();
+                    Ok(InferenceFeedback::Continue)
+                },
+            );
 
             match res {
-                Ok(_) => format!("{}", answer),
-                Err(err) => format!("\n{err}"),
+                Ok(output) => Ok(completion(&app_handle, running_job, answer, output)),
+                Err(err) => Err(CommandError::from(err)),
             }
         }
         None => {
             println!("No model loaded");
-            return "Error: No model loaded".to_string();
+            return Err(CommandError::NoModelLoaded);
+        }
+    }
+}
+
+/// Builds the completion of a finished generation and records its stats.
+fn completion(
+    app_handle: &tauri::AppHandle,
+    running_job: &RunningJob,
+    answer: String,
+    output: InferenceOutput,
+) -> Completion {
+    let stats = CompletionStats::new(&output.stats);
+    inference_stats::record(app_handle, &stats);
+    return Completion {
+        request_id: running_job.id,
+        answer,
+        stop_reason: output.stop_reason,
+        stats,
+    };
+}
+
+/// Asks a question and returns the answer as JSON. The answer is constrained
+/// to `grammar` when given, otherwise to `schema`, or to any JSON object when
+/// neither is given. Answers for a schema are also validated against it.
+#[tauri::command]
+pub async fn ask_structured(
+    message: String,
+    schema: Option<serde_json::Value>,
+    grammar: Option<String>,
+    app_handle: tauri::AppHandle,
+) -> Result<serde_json::Value, String> {
+    println!("Command: ask_structured");
+    let grammar_text = match (&grammar, &schema) {
+        (Some(grammar), _) => grammar.clone(),
+        (None, Some(schema)) => json_schema::to_grammar(schema).map_err(|err| err.to_string())?,
+        (None, None) => json_schema::any_object_grammar(),
+    };
+    let compiled_grammar = Arc::new(Grammar::parse(&grammar_text).map_err(|err| err.to_string())?);
+    let prompt_message = match &schema {
+        Some(schema) => format!(
+            "{}\nAnswer with JSON that follows this schema: {}",
+            message, schema
+        ),
+        None => message,
+    };
+
+    let worker = app_handle.state::<InferenceWorker>();
+    let job_app_handle = app_handle.clone();
+    let job = worker.submit(move |context, running_job| {
+        run_ask_structured(
+            prompt_message,
+            compiled_grammar,
+            job_app_handle,
+            context,
+            running_job,
+        )
+    });
+    let answer = job.wait().await.map_err(|err| err.to_string())??;
+
+    let value: serde_json::Value = serde_json::from_str(answer.trim())
+        .map_err(|err| format!("The answer is not valid JSON: {}", err))?;
+    if grammar.is_none() {
+        if let Some(schema) = &schema {
+            json_schema::validate(schema, &value).map_err(|err| err.to_string())?;
+        }
+    }
+    return Ok(value);
+}
+
+fn run_ask_structured(
+    message: String,
+    grammar: Arc<Grammar>,
+    app_handle: tauri::AppHandle,
+    context: &WorkerContext,
+    running_job: &RunningJob,
+) -> Result<String, String> {
+    let model = context.model.as_ref().ok_or("No model loaded")?;
+    let profile = profiles::get_effective_profile(&app_handle);
+    let prompt_template = profile.prompt_template.value;
+    let mut prompt = message;
+    if prompt_template.contains("[[message]]") {
+        prompt = prompt_template.replace("[[message]]", &prompt);
+    }
+    println!("Prompt: {}", prompt);
+
+    let mut session = model.start_session(session_config(&profile.parameters.value));
+    let output = start_inference(
+        &app_handle,
+        model,
+        running_job,
+        &mut session,
+        prompt,
+        InferenceOptions {
+            grammar: Some(grammar),
+            ..Default::default()
+        },
+        |_, _| Ok(InferenceFeedback::Continue),
+    )
+    .map_err(|err| err.to_string())?;
+    if output.stop_reason == StopReason::Cancelled {
+        return Err("The request was cancelled".to_string());
+    }
+    return Ok(output.generated_text);
+}
+
+#[derive(Clone, serde::Serialize)]
+struct TitledPayload {
+    conversation_id: ConversationId,
+    title: String,
+}
+
+/// Queues a low priority job that has the model write a title for the
+/// conversation, once there is a first exchange and while the title is still
+/// the one taken from the first message.
+fn request_title(app_handle: &tauri::AppHandle, conversation: &Conversation) {
+    let conversation_id = match conversation.id() {
+        Some(conversation_id) => conversation_id,
+        None => return,
+    };
+    match app_handle
+        .state::<ConversationStore>()
+        .needs_title(conversation_id)
+    {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(err) => {
+            println!("Error reading conversation: {}", err);
+            return;
+        }
+    }
+    let exchange: Vec<Message> = conversation.selected_path().into_iter().take(2).collect();
+    if exchange.len() < 2 {
+        return;
+    }
+
+    let worker = app_handle.state::<InferenceWorker>();
+    let job_app_handle = app_handle.clone();
+    worker.submit_with_priority(JobPriority::Low, move |context, running_job| {
+        run_title(
+            conversation_id,
+            exchange,
+            job_app_handle,
+            context,
+            running_job,
+        )
+    });
+}
+
+fn run_title(
+    conversation_id: ConversationId,
+    exchange: Vec<Message>,
+    app_handle: tauri::AppHandle,
+    context: &WorkerContext,
+    running_job: &RunningJob,
+) {
+    let model = match context.model.as_ref() {
+        Some(model) => model,
+        None => return,
+    };
+    // Another request may have written it while this one waited
+    let store = app_handle.state::<ConversationStore>();
+    if !store.needs_title(conversation_id).unwrap_or(false) {
+        return;
+    }
+
+    let profile = profiles::get_effective_profile(&app_handle);
+    let mut request = Conversation::default();
+    for message in exchange {
+        let excerpt: String = message.text.chars().take(TITLE_EXCERPT_LENGTH).collect();
+        request.push(message.role, excerpt);
+    }
+    request.push(MessageRole::Human, TITLE_REQUEST.to_string());
+    let prompt = profile.chat_template.value.render(&request.selected_path());
+    println!("Title prompt: {}", prompt);
+
+    let mut session = model.start_session(session_config(&profile.parameters.value));
+    let mut answer = String::new();
+    let output = start_inference(
+        &app_handle,
+        model,
+        running_job,
+        &mut session,
+        prompt,
+        InferenceOptions {
+            maximum_token_count: Some(TITLE_TOKENS),
+            ..Default::default()
+        },
+        |token, _| {
+            answer.push_str(&token);
+            // Only the first line is used
+            if answer.trim_start().contains('\n') {
+                return Ok(InferenceFeedback::Halt);
+            }
+            Ok(InferenceFeedback::Continue)
+        },
+    );
+    match output {
+        Ok(output) if output.stop_reason == StopReason::Cancelled => {
+            println!("Title generation gave way to a request");
+            return;
+        }
+        Ok(_) => {}
+        Err(err) => {
+            println!("Error generating title: {}", err);
+            return;
         }
     }
+
+    let title = clean_title(&answer);
+    if title.is_empty() {
+        return;
+    }
+    match store.set_generated_title(conversation_id, &title) {
+        Ok(true) => app_handle
+            .emit_all(
+                "conversation_titled",
+                TitledPayload {
+                    conversation_id,
+                    title,
+                },
+            )
+            .unwrap(),
+        Ok(false) => {}
+        Err(err) => println!("Error saving title: {}", err),
+    }
+}
+
+/// The first line of the answer, without quotes, a `Title:` label or a final
+/// period.
+fn clean_title(answer: &str) -> String {
+    let line = answer
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or("");
+    let line = match line.split_once(':') {
+        Some((label, rest)) if label.trim().eq_ignore_ascii_case("title") => rest.trim(),
+        _ => line,
+    };
+    let title = line
+        .trim_matches(|c: char| matches!(c, '"' | '\'' | '*' | '#'))
+        .trim_end_matches('.')
+        .trim();
+    return title.chars().take(TITLE_MAX_LENGTH).collect();
 }
 
 #[tauri::command]
@@ -321,16 +1229,21 @@ pub async fn delete_model(
 pub fn load_model(
     model_path: &PathBuf,
     arquitecture: &str,
+    parameters: &InferenceParameters,
 ) -> Result<Box<dyn llm::Model>, LoadError> {
     println!("Loading model:");
     println!("- Path: {}", model_path.display());
     println!("- Arquitecture: {}", arquitecture);
+    println!("- Context size: {}", parameters.context_size);
 
     let model = llm::load_dynamic(
         arquitecture.parse().unwrap_or_else(|e| panic!("{e}")),
         model_path,
         llm::VocabularySource::Model,
-        Default::default(),
+        llm::ModelParameters {
+            context_size: parameters.context_size,
+            ..Default::default()
+        },
         load_callback,
     );
     match model {
@@ -345,112 +1258,294 @@ pub fn load_model(
     }
 }
 
+/// Runs the prompt through the session and streams the answer to the callback,
+/// with the probabilities of the streamed tokens when the options ask for
+/// them. With a grammar, only text the grammar allows is generated and stop
+/// sequences are ignored.
+///
+/// The output holds the text exactly as the model generated it. The streamed
+/// answer can be shorter, since it never includes a stop sequence.
 fn start_inference(
     app_handle: &tauri::AppHandle,
     model: &Box<dyn llm::Model>,
+    running_job: &RunningJob,
+    session: &mut llm::InferenceSession,
     prompt: String,
-    mut inference_token_callback: impl FnMut(String) -> Result<InferenceFeedback, Infallible>,
-) -> Result<InferenceStats, InferenceError> {
-    reset_stop_infering(&app_handle);
+    options: InferenceOptions,
+    mut inference_token_callback: impl FnMut(
+        String,
+        Option<Vec<TokenProbability>>,
+    ) -> Result<InferenceFeedback, Infallible>,
+) -> Result<InferenceOutput, InferenceError> {
+    let profile = profiles::get_effective_profile(app_handle);
+    let parameters = profile.parameters.value;
+    let maximum_token_count = options
+        .maximum_token_count
+        .unwrap_or(parameters.maximum_token_count);
+    let mut rng = match parameters.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
 
-    let mut session = model.start_session(Default::default());
-    return session.infer::<Infallible>(
+    let mut inference_parameters = build_parameters(&parameters);
+    let stop_sequences = match options.grammar {
+        Some(grammar) => {
+            inference_parameters.sampler = Arc::new(GrammarSampler::new(
+                inference_parameters.sampler,
+                grammar,
+                model.as_ref(),
+            ));
+            Vec::new()
+        }
+        None => profile.stop_sequences.value,
+    };
+    let sampled_tokens = Arc::new(Mutex::new(Vec::new()));
+    if let Some(alternative_count) = options.top_logprobs {
+        inference_parameters.sampler = Arc::new(ProbabilitySampler::new(
+            inference_parameters.sampler,
+            alternative_count,
+            Arc::clone(&sampled_tokens),
+        ));
+    }
+    // Probabilities of the tokens whose text hasn't been streamed yet
+    let mut pending_probabilities: Vec<TokenProbability> = Vec::new();
+
+    let mut generated_text = "".to_string();
+    // Length of the generated text passed to the callback
+    let mut streamed_len = 0;
+    let mut generated_tokens = 0;
+    let mut stop_reason = None;
+    let mut prompt_fed = true;
+    let mut stop_sequence_matcher = StopSequenceMatcher::new(stop_sequences);
+    let stats = session.infer::<Infallible>(
         model.as_ref(),
-        &mut rand::thread_rng(),
+        &mut rng,
         &InferenceRequest {
             prompt: Prompt::Text(&prompt),
             play_back_previous_tokens: false,
-            parameters: &build_parameters(app_handle.clone()),
-            maximum_token_count: Some(1000),
+            parameters: &inference_parameters,
+            maximum_token_count: Some(maximum_token_count),
         },
         &mut Default::default(),
         |inference_response| match inference_response {
-            llm::InferenceResponse::PromptToken(_) => Ok(llm::InferenceFeedback::Continue),
+            llm::InferenceResponse::PromptToken(_) => {
+                // A long prompt doesn't hold up the request that cancelled it
+                if running_job.is_cancelled() {
+                    println!("Stop reading the prompt");
+                    prompt_fed = false;
+                    stop_reason = Some(StopReason::Cancelled);
+                    return Ok(InferenceFeedback::Halt);
+                }
+                Ok(llm::InferenceFeedback::Continue)
+            }
             llm::InferenceResponse::InferredToken(t) => {
-                if stop_infering(&app_handle) {
+                if running_job.is_cancelled() {
                     println!("Stop infering");
+                    stop_reason = Some(StopReason::Cancelled);
+                    return Ok(InferenceFeedback::Halt);
+                }
+                generated_tokens += 1;
+                generated_text.push_str(&t);
+                pending_probabilities.extend(
+                    sampled_tokens
+                        .lock()
+                        .unwrap()
+                        .drain(..)
+                        .map(|sampled_token| token_probability(model.as_ref(), sampled_token)),
+                );
+                let (text, stop_sequence_found) = stop_sequence_matcher.push(&t);
+                streamed_len += text.len();
+                let feedback = if text.is_empty() {
+                    InferenceFeedback::Continue
+                } else {
+                    let probabilities = options
+                        .top_logprobs
+                        .map(|_| std::mem::take(&mut pending_probabilities));
+                    inference_token_callback(text, probabilities)?
+                };
+                if stop_sequence_found {
+                    println!("Stop sequence found");
+                    stop_reason = Some(StopReason::StopSequence);
                     return Ok(InferenceFeedback::Halt);
                 }
-                return inference_token_callback(t);
+                return Ok(feedback);
             }
             _ => Ok(llm::InferenceFeedback::Continue),
         },
-    );
+    )?;
+
+    let pending_text = stop_sequence_matcher.flush();
+    if !pending_text.is_empty() {
+        let probabilities = options.top_logprobs.map(|_| pending_probabilities);
+        let _ = inference_token_callback(pending_text, probabilities);
+    }
+    // The stop sequence was evaluated too. Taking it back out of the session
+    // lets the next prompt, which has the answer without it, continue it.
+    let mut session_text = generated_text.clone();
+    if stop_reason == Some(StopReason::StopSequence) {
+        if let Some(kept_len) =
+            rewind_stop_sequence(model.as_ref(), session, &generated_text, streamed_len)
+        {
+            session_text.truncate(kept_len);
+        }
+    }
+    let stop_reason = stop_reason.unwrap_or(if generated_tokens >= maximum_token_count {
+        StopReason::MaxTokens
+    } else {
+        StopReason::EndOfText
+    });
+    return Ok(InferenceOutput {
+        stats,
+        generated_text,
+        session_text,
+        prompt_fed,
+        stop_reason,
+    });
 }
 
-fn build_parameters(app_handle: tauri::AppHandle) -> llm::InferenceParameters {
-    let top_p_top_ksampler = llm::samplers::TopPTopK {
-        top_p: localstore::get_top_p(app_handle.clone())
-            .parse::<f32>()
-            .unwrap(),
-        top_k: localstore::get_top_k(app_handle.clone())
-            .parse::<usize>()
-            .unwrap(),
-        repeat_penalty: localstore::get_repetition_penalty(app_handle.clone())
-            .parse::<f32>()
-            .unwrap(),
-        temperature: localstore::get_temperature(app_handle)
-            .parse::<f32>()
-            .unwrap(),
-        ..Default::default()
+/// Rewinds the session past the tokens of the stop sequence at the end of the
+/// generated text. Returns how much of the generated text the session still
+/// holds, or `None` when it couldn't be rewound.
+fn rewind_stop_sequence(
+    model: &dyn llm::Model,
+    session: &mut llm::InferenceSession,
+    generated_text: &str,
+    streamed_len: usize,
+) -> Option<usize> {
+    let stop_len = generated_text.len() - streamed_len;
+    // A token can hold the end of the answer and the start of the stop
+    // sequence, then that end of the answer is rewound as well
+    let mut rewound_len = 0;
+    let mut rewound_tokens = 0;
+    for token_id in session.tokens().iter().rev() {
+        if rewound_len >= stop_len {
+            break;
+        }
+        rewound_len += model.vocabulary().token(*token_id as usize).len();
+        rewound_tokens += 1;
+    }
+    if rewound_len > generated_text.len()
+        || !generated_text.is_char_boundary(generated_text.len() - rewound_len)
+    {
+        return None;
+    }
+    match session.rewind(model, rewound_tokens) {
+        Ok(_) => return Some(generated_text.len() - rewound_len),
+        Err(err) => {
+            println!("Error rewinding the stop sequence: {}", err);
+            return None;
+        }
+    }
+}
+
+fn token_probability(model: &dyn llm::Model, sampled_token: SampledToken) -> TokenProbability {
+    return TokenProbability {
+        token: token_text(model, sampled_token.token_id),
+        logprob: sampled_token.logprob,
+        alternatives: sampled_token
+            .alternatives
+            .into_iter()
+            .map(|(token_id, logprob)| TokenAlternative {
+                token: token_text(model, token_id),
+                logprob,
+            })
+            .collect(),
     };
+}
 
-    return llm::InferenceParameters {
-        sampler: Arc::new(top_p_top_ksampler),
+fn token_text(model: &dyn llm::Model, token_id: TokenId) -> String {
+    return String::from_utf8_lossy(&model.vocabulary().token(token_id as usize)).to_string();
+}
+
+fn session_config(parameters: &InferenceParameters) -> llm::InferenceSessionConfig {
+    return llm::InferenceSessionConfig {
+        n_threads: parameters.thread_count,
         ..Default::default()
     };
 }
 
-pub(crate) struct SessionState {
-    pub should_stop_infering: Mutex<bool>,
+fn build_parameters(parameters: &InferenceParameters) -> llm::InferenceParameters {
+    return llm::InferenceParameters {
+        sampler: samplers::build_sampler(&parameters.sampler),
+        ..Default::default()
+    };
 }
 
 #[tauri::command]
-pub async fn cancel_inference(app_handle: tauri::AppHandle) {
-    let session_state = app_handle.state::<SessionState>();
-    let mut should_stop_infering = session_state.inner().should_stop_infering.lock().unwrap();
-    *should_stop_infering = true;
+pub async fn cancel_inference(request_id: JobId, app_handle: tauri::AppHandle) -> bool {
+    let worker = app_handle.state::<InferenceWorker>();
+    return worker.cancel(request_id);
 }
 
+/// Saves the values the user changed into the profile of the active model.
 #[tauri::command]
 pub async fn save_parameters(
-    prompt_template: &str,
-    temperature: &str,
-    top_p: &str,
-    top_k: &str,
-    repetition_penalty: &str,
+    update: ProfileUpdate,
     app_handle: tauri::AppHandle,
 ) -> Result<(), String> {
-    localstore::save_parameters(
-        app_handle,
-        prompt_template,
-        temperature,
-        top_p,
-        top_k,
-        repetition_penalty,
-    )?;
+    println!("Command: save_parameters");
+    if let Some(parameters) = &update.parameters {
+        parameters.validate().map_err(|err| err.to_string())?;
+    }
+    let previous_context_size = profiles::get_effective_profile(&app_handle)
+        .parameters
+        .value
+        .context_size;
+    profiles::save_profile(&app_handle, update)?;
+    let context_size = profiles::get_effective_profile(&app_handle)
+        .parameters
+        .value
+        .context_size;
+
+    // The context size is fixed when the model is loaded
+    if previous_context_size != context_size {
+        if let Some(current_model) = localstore::get_active_model(app_handle.clone()) {
+            swap_model(
+                current_model.filename,
+                current_model.name,
+                current_model.arquitecture,
+                true,
+                app_handle,
+            )
+            .await?;
+        }
+    }
 
     return Ok(());
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
-pub struct InferenceParameters {
+#[derive(serde::Serialize, Debug)]
+pub struct ParameterSources {
+    pub prompt_template: ParameterSource,
+    pub chat_template: ParameterSource,
+    pub parameters: ParameterSource,
+    pub stop_sequences: ParameterSource,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct GetParametersResponse {
     pub prompt_template: String,
-    pub temperature: String,
-    pub top_p: String,
-    pub top_k: String,
-    pub repetition_penalty: String,
+    pub parameters: InferenceParameters,
+    pub stop_sequences: Vec<String>,
+    pub sources: ParameterSources,
 }
 
+/// Returns the values that apply to the active model and where each of them
+/// comes from.
 #[tauri::command]
-pub async fn get_parameters(app_handle: tauri::AppHandle) -> Result<InferenceParameters, String> {
-    let parameters = InferenceParameters {
-        prompt_template: localstore::get_prompt_template(app_handle.clone()),
-        temperature: localstore::get_temperature(app_handle.clone()),
-        top_p: localstore::get_top_p(app_handle.clone()),
-        top_k: localstore::get_top_k(app_handle.clone()),
-        repetition_penalty: localstore::get_repetition_penalty(app_handle.clone()),
+pub async fn get_parameters(app_handle: tauri::AppHandle) -> Result<GetParametersResponse, String> {
+    let profile = profiles::get_effective_profile(&app_handle);
+    let response = GetParametersResponse {
+        prompt_template: profile.prompt_template.value,
+        parameters: profile.parameters.value,
+        stop_sequences: profile.stop_sequences.value,
+        sources: ParameterSources {
+            prompt_template: profile.prompt_template.source,
+            chat_template: profile.chat_template.source,
+            parameters: profile.parameters.source,
+            stop_sequences: profile.stop_sequences.source,
+        },
     };
 
-    return Ok(parameters);
+    return Ok(response);
 }
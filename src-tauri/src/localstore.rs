@@ -3,6 +3,11 @@ use rust_decimal_macros::dec;
 use serde_json::json;
 use tauri_plugin_store::{Store, StoreBuilder};
 
+use crate::parameters::{InferenceParameters, SamplerParameters};
+use crate::presets::Preset;
+use crate::profiles::ParameterProfile;
+use crate::prompt_template::ChatTemplate;
+
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct CurrentLanguageModel {
     pub name: String,
@@ -46,6 +51,36 @@ pub(crate) fn get_prompt_template(app_handle: tauri::AppHandle) -> String {
     }
 }
 
+pub(crate) fn get_chat_template(app_handle: tauri::AppHandle) -> Option<ChatTemplate> {
+    let store = load_store(app_handle);
+    match store.get("chat_template".to_string()) {
+        Some(value) => match serde_json::from_value(value.clone()) {
+            Ok(chat_template) => return Some(chat_template),
+            Err(err) => {
+                println!("Invalid chat template, ignoring it: {}", err);
+                return None;
+            }
+        },
+        None => {
+            println!("No chat template found");
+            return None;
+        }
+    }
+}
+
+pub(crate) fn save_chat_template(
+    app_handle: tauri::AppHandle,
+    chat_template: &ChatTemplate,
+) -> Result<(), String> {
+    let mut store = load_store(app_handle);
+    store
+        .insert("chat_template".to_string(), json!(chat_template))
+        .unwrap();
+    store.save().unwrap();
+
+    Ok(())
+}
+
 pub(crate) fn get_current_model_filename(app_handle: tauri::AppHandle) -> String {
     if let Some(model) = get_active_model(app_handle) {
         return model.filename;
@@ -70,72 +105,170 @@ pub(crate) fn save_prompt_template(
 
 pub(crate) fn save_parameters(
     app_handle: tauri::AppHandle,
-    prompt_template: &str,
-    temperature: &str,
-    top_p: &str,
-    top_k: &str,
-    repetition_penalty: &str,
+    parameters: &InferenceParameters,
 ) -> Result<(), String> {
-    save_prompt_template(app_handle.clone(), prompt_template)?;
     let mut store = load_store(app_handle);
     store
-        .insert("temperature".to_string(), json!(temperature))
+        .insert("inference_parameters".to_string(), json!(parameters))
         .unwrap();
-    store.insert("top_p".to_string(), json!(top_p)).unwrap();
-    store.insert("top_k".to_string(), json!(top_k)).unwrap();
-    store
-        .insert("repetition_penalty".to_string(), json!(repetition_penalty))
-        .unwrap();
-
     store.save().unwrap();
 
     Ok(())
 }
 
-pub(crate) fn get_temperature(app_handle: tauri::AppHandle) -> String {
-    let store = load_store(app_handle);
-    match store.get("temperature".to_string()) {
-        Some(value) => return serde_json::from_value(value.clone()).unwrap(),
+pub(crate) fn get_parameters(app_handle: tauri::AppHandle) -> InferenceParameters {
+    let mut store = load_store(app_handle);
+    match store.get("inference_parameters".to_string()) {
+        Some(value) => return serde_json::from_value(value.clone()).unwrap_or_default(),
         None => {
-            println!("No temperature found");
-            return "0.8".to_string();
+            println!("No inference_parameters found, migrating old values");
+            let parameters = migrate_string_parameters(|key| store.get(key.to_string()).cloned());
+            for key in LEGACY_PARAMETER_KEYS {
+                let _ = store.delete(key.to_string());
+            }
+            store
+                .insert("inference_parameters".to_string(), json!(parameters))
+                .unwrap();
+            store.save().unwrap();
+            return parameters;
         }
     }
 }
 
-pub(crate) fn get_top_p(app_handle: tauri::AppHandle) -> String {
+/// Keys of the parameters saved one by one before `inference_parameters`
+const LEGACY_PARAMETER_KEYS: [&str; 4] = ["temperature", "top_p", "top_k", "repetition_penalty"];
+
+/// Parameters used to be saved one by one as strings. Values that can't be
+/// parsed or don't validate are replaced by the defaults.
+fn migrate_string_parameters(
+    legacy_value: impl Fn(&str) -> Option<serde_json::Value>,
+) -> InferenceParameters {
+    fn parse_legacy<T: std::str::FromStr>(value: Option<serde_json::Value>, default: T) -> T {
+        return value
+            .and_then(|value| value.as_str().and_then(|value| value.trim().parse().ok()))
+            .unwrap_or(default);
+    }
+
+    let defaults = InferenceParameters::default();
+    let parameters = InferenceParameters {
+        sampler: SamplerParameters {
+            temperature: parse_legacy(legacy_value("temperature"), defaults.sampler.temperature),
+            top_p: parse_legacy(legacy_value("top_p"), defaults.sampler.top_p),
+            top_k: parse_legacy(legacy_value("top_k"), defaults.sampler.top_k),
+            repetition_penalty: parse_legacy(
+                legacy_value("repetition_penalty"),
+                defaults.sampler.repetition_penalty,
+            ),
+            ..defaults.sampler.clone()
+        },
+        ..defaults.clone()
+    };
+    match parameters.validate() {
+        Ok(_) => parameters,
+        Err(err) => {
+            println!("Discarding old parameters: {}", err);
+            defaults
+        }
+    }
+}
+
+/// Stop sequences set in the parameters. `None` means they were never
+/// overridden and the ones from the model catalog apply.
+pub(crate) fn get_stop_sequences(app_handle: tauri::AppHandle) -> Option<Vec<String>> {
     let store = load_store(app_handle);
-    match store.get("top_p".to_string()) {
-        Some(value) => return serde_json::from_value(value.clone()).unwrap(),
+    match store.get("stop_sequences".to_string()) {
+        Some(value) => match serde_json::from_value(value.clone()) {
+            Ok(stop_sequences) => return stop_sequences,
+            Err(err) => {
+                println!("Invalid stop_sequences, ignoring them: {}", err);
+                return None;
+            }
+        },
+        None => {
+            println!("No stop_sequences found");
+            return None;
+        }
+    }
+}
+
+pub(crate) fn save_stop_sequences(
+    app_handle: tauri::AppHandle,
+    stop_sequences: Option<Vec<String>>,
+) -> Result<(), String> {
+    let mut store = load_store(app_handle);
+    match stop_sequences {
+        Some(stop_sequences) => {
+            store
+                .insert("stop_sequences".to_string(), json!(stop_sequences))
+                .unwrap();
+        }
         None => {
-            println!("No top_p found");
-            return "0.95".to_string();
+            store.delete("stop_sequences".to_string()).unwrap();
         }
     }
+    store.save().unwrap();
+
+    Ok(())
 }
 
-pub(crate) fn get_top_k(app_handle: tauri::AppHandle) -> String {
+pub(crate) fn get_parameter_profile(
+    app_handle: tauri::AppHandle,
+    model_filename: &str,
+) -> Option<ParameterProfile> {
     let store = load_store(app_handle);
-    match store.get("top_k".to_string()) {
-        Some(value) => return serde_json::from_value(value.clone()).unwrap(),
+    match store.get("parameter_profiles".to_string()) {
+        Some(value) => {
+            return value
+                .get(model_filename)
+                .map(|profile| serde_json::from_value(profile.clone()).unwrap_or_default())
+        }
         None => {
-            println!("No top_k found");
-            return "40".to_string();
+            println!("No parameter_profiles found");
+            return None;
         }
     }
 }
 
-pub(crate) fn get_repetition_penalty(app_handle: tauri::AppHandle) -> String {
+pub(crate) fn save_parameter_profile(
+    app_handle: tauri::AppHandle,
+    model_filename: &str,
+    profile: &ParameterProfile,
+) -> Result<(), String> {
+    let mut store = load_store(app_handle);
+    let mut profiles = store
+        .get("parameter_profiles".to_string())
+        .cloned()
+        .unwrap_or_else(|| json!({}));
+    profiles[model_filename] = json!(profile);
+    store
+        .insert("parameter_profiles".to_string(), profiles)
+        .unwrap();
+    store.save().unwrap();
+
+    Ok(())
+}
+
+/// Presets created or imported by the user, the built-in ones live in
+/// `configs/presets.json`.
+pub(crate) fn get_presets(app_handle: tauri::AppHandle) -> Vec<Preset> {
     let store = load_store(app_handle);
-    match store.get("repetition_penalty".to_string()) {
-        Some(value) => return serde_json::from_value(value.clone()).unwrap(),
+    match store.get("presets".to_string()) {
+        Some(value) => return serde_json::from_value(value.clone()).unwrap_or_default(),
         None => {
-            println!("No repetition_penalty found");
-            return "1.3".to_string();
+            println!("No presets found");
+            return vec![];
         }
     }
 }
 
+pub(crate) fn save_presets(app_handle: tauri::AppHandle, presets: &[Preset]) -> Result<(), String> {
+    let mut store = load_store(app_handle);
+    store.insert("presets".to_string(), json!(presets)).unwrap();
+    store.save().unwrap();
+
+    Ok(())
+}
+
 pub(crate) fn save_models_folder(
     app_handle: tauri::AppHandle,
     models_folder: String,
@@ -193,3 +326,56 @@ fn do_first_time(store: &mut Store<tauri::Wry>, app_handle: &tauri::AppHandle) {
         store.save().unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn migrate(legacy: serde_json::Value) -> InferenceParameters {
+        return migrate_string_parameters(|key| legacy.get(key).cloned());
+    }
+
+    #[test]
+    fn migrates_valid_string_parameters() {
+        let parameters = migrate(json!({
+            "temperature": "0.5",
+            "top_p": " 0.9 ",
+            "top_k": "20",
+            "repetition_penalty": "1.1"
+        }));
+        let defaults = InferenceParameters::default();
+        assert_eq!(parameters.sampler.temperature, 0.5);
+        assert_eq!(parameters.sampler.top_p, 0.9);
+        assert_eq!(parameters.sampler.top_k, 20);
+        assert_eq!(parameters.sampler.repetition_penalty, 1.1);
+        assert_eq!(parameters.sampler.min_p, defaults.sampler.min_p);
+        assert_eq!(parameters.context_size, defaults.context_size);
+    }
+
+    #[test]
+    fn replaces_values_that_do_not_parse() {
+        let parameters = migrate(json!({
+            "temperature": "abc",
+            "top_p": 0.9,
+            "top_k": "20.5",
+            "repetition_penalty": "1.1"
+        }));
+        let defaults = InferenceParameters::default().sampler;
+        assert_eq!(parameters.sampler.temperature, defaults.temperature);
+        // Only strings were ever saved
+        assert_eq!(parameters.sampler.top_p, defaults.top_p);
+        assert_eq!(parameters.sampler.top_k, defaults.top_k);
+        assert_eq!(parameters.sampler.repetition_penalty, 1.1);
+    }
+
+    #[test]
+    fn discards_parameters_out_of_range() {
+        let defaults = InferenceParameters::default();
+        assert_eq!(
+            migrate(json!({ "temperature": "7", "top_k": "20" })),
+            defaults
+        );
+        assert_eq!(migrate(json!({ "top_p": "NaN" })), defaults);
+        assert_eq!(migrate(json!({})), defaults);
+    }
+}
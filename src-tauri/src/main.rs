@@ -1,18 +1,33 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+use conversation::Conversation;
 use downloader::DownloadState;
 
+use inference_worker::InferenceWorker;
 use std::{path::PathBuf, sync::Mutex, vec};
 use tauri::Manager;
 
+mod completion;
 mod configs;
+mod conversation;
+mod conversation_store;
 mod downloader;
+mod export;
+mod grammar;
+mod importers;
+mod inference_stats;
+mod inference_worker;
+mod json_schema;
 mod language_model;
 mod localstore;
-struct AppState {
-    model: Mutex<Option<Box<dyn llm::Model>>>,
-}
+mod parameters;
+mod presets;
+mod profiles;
+mod prompt_template;
+mod samplers;
+mod snapshots;
+mod stop_sequences;
 
 fn main() {
     tauri::Builder::default()
@@ -20,60 +35,92 @@ fn main() {
         .setup(|app| {
             let app_handle = app.app_handle();
 
-            let model: Option<Box<dyn llm::Model>> = match localstore::get_active_model(app_handle)
-            {
-                Some(current_model) => {
-                    match language_model::load_model(
-                        &PathBuf::from(current_model.path),
-                        &current_model.arquitecture,
-                    ) {
-                        Ok(model) => {
-                            println!("Loaded model: {:?}", current_model.name);
-                            Some(model)
-                        }
-                        Err(e) => {
-                            println!("Error loading model: {:?}", e);
-                            None
+            let model: Option<Box<dyn llm::Model>> =
+                match localstore::get_active_model(app_handle.clone()) {
+                    Some(current_model) => {
+                        match language_model::load_model(
+                            &PathBuf::from(current_model.path),
+                            &current_model.arquitecture,
+                            &profiles::get_effective_profile(&app_handle)
+                                .parameters
+                                .value,
+                        ) {
+                            Ok(model) => {
+                                println!("Loaded model: {:?}", current_model.name);
+                                Some(model)
+                            }
+                            Err(e) => {
+                                println!("Error loading model: {:?}", e);
+                                None
+                            }
                         }
                     }
-                }
-                None => {
-                    println!("No current model");
-                    None
-                }
-            };
+                    None => {
+                        println!("No current model");
+                        None
+                    }
+                };
 
-            app.manage(AppState {
-                model: Mutex::from(model),
-            });
+            app.manage(InferenceWorker::start(model));
+            app.manage(conversation_store::ConversationStore::open(&app_handle)?);
             app.manage(language_model::ChatState {
-                messages: Mutex::from(vec![]),
+                conversation: Mutex::from(Conversation::default()),
+                session: Mutex::from(None),
             });
             app.manage(DownloadState {
                 tokio_handle: Mutex::from(None),
             });
-            app.manage(language_model::SessionState {
-                should_stop_infering: Mutex::from(false),
-            });
+            language_model::restore_chat_session(&app_handle);
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             language_model::chat,
+            language_model::regenerate_message,
+            language_model::continue_message,
+            language_model::edit_message,
             language_model::ask,
+            language_model::ask_structured,
             language_model::get_language_models,
             language_model::set_current_model,
             language_model::delete_model,
             language_model::get_prompt_template,
+            language_model::get_chat_template,
             language_model::get_active_model,
             language_model::cancel_inference,
+            language_model::get_queue_position,
             language_model::save_parameters,
             language_model::get_parameters,
+            language_model::get_chat_history,
+            language_model::select_branch,
+            language_model::new_chat,
+            conversation_store::list_conversations,
+            conversation_store::open_conversation,
+            conversation_store::search_conversations,
+            conversation_store::rename_conversation,
+            conversation_store::delete_conversation,
+            export::export_conversation,
+            export::export_all_conversations,
+            importers::import_conversations,
+            presets::get_presets,
+            presets::create_preset,
+            presets::duplicate_preset,
+            presets::rename_preset,
+            presets::delete_preset,
+            presets::apply_preset,
+            presets::export_preset,
+            presets::import_preset,
+            inference_stats::get_inference_stats,
             downloader::download_model,
             downloader::cancel_download,
             configs::show_in_folder,
             configs::choose_directory,
             configs::get_models_folder,
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app_handle, event| {
+            if let tauri::RunEvent::Exit = event {
+                language_model::save_chat_session(app_handle);
+            }
+        });
 }
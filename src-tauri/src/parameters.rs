@@ -0,0 +1,259 @@
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{name} must be between {min} and {max}, got {value}")]
+    OutOfRange {
+        name: &'static str,
+        min: f64,
+        max: f64,
+        value: f64,
+    },
+}
+
+/// Part of the context window that max tokens can't take, so there is
+/// always room for the prompt
+const MINIMUM_PROMPT_TOKENS: usize = 64;
+
+/// Strategy used to pick the next token from the logits.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SamplerStrategy {
+    #[default]
+    TopPTopK,
+    /// Mirostat v1, uses `mirostat_tau` and `mirostat_eta`
+    Mirostat,
+    /// Mirostat v2, uses `mirostat_tau` and `mirostat_eta`
+    MirostatV2,
+    /// Locally typical sampling, uses `typical_p`
+    Typical,
+    /// Tail-free sampling, uses `tail_free_z`
+    TailFree,
+    /// Uses `min_p`
+    MinP,
+}
+
+/// How the next token is picked. This is the part of the parameters that
+/// presets carry.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SamplerParameters {
+    pub strategy: SamplerStrategy,
+    pub temperature: f32,
+    pub top_p: f32,
+    pub top_k: usize,
+    pub repetition_penalty: f32,
+    /// How many of the last tokens the repeat penalty looks at
+    pub repetition_penalty_last_n: usize,
+    /// Target surprise of Mirostat
+    pub mirostat_tau: f32,
+    /// Learning rate of Mirostat
+    pub mirostat_eta: f32,
+    pub typical_p: f32,
+    pub tail_free_z: f32,
+    /// Smallest probability kept, relative to the most likely token
+    pub min_p: f32,
+}
+
+impl Default for SamplerParameters {
+    fn default() -> Self {
+        SamplerParameters {
+            strategy: SamplerStrategy::default(),
+            temperature: 0.8,
+            top_p: 0.95,
+            top_k: 40,
+            repetition_penalty: 1.3,
+            repetition_penalty_last_n: 64,
+            mirostat_tau: 5.0,
+            mirostat_eta: 0.1,
+            typical_p: 0.95,
+            tail_free_z: 0.95,
+            min_p: 0.05,
+        }
+    }
+}
+
+impl SamplerParameters {
+    pub fn validate(&self) -> Result<()> {
+        check_range("Temperature", self.temperature as f64, 0.01, 2.0)?;
+        check_range("Top P", self.top_p as f64, 0.01, 1.0)?;
+        check_range("Top K", self.top_k as f64, 1.0, 1000.0)?;
+        check_range("Repeat penalty", self.repetition_penalty as f64, 0.5, 2.0)?;
+        check_range(
+            "Repeat penalty window",
+            self.repetition_penalty_last_n as f64,
+            0.0,
+            4096.0,
+        )?;
+        check_range("Mirostat tau", self.mirostat_tau as f64, 0.0, 10.0)?;
+        check_range("Mirostat eta", self.mirostat_eta as f64, 0.0, 1.0)?;
+        check_range("Typical P", self.typical_p as f64, 0.01, 1.0)?;
+        check_range("Tail free Z", self.tail_free_z as f64, 0.01, 1.0)?;
+        check_range("Min P", self.min_p as f64, 0.0, 1.0)?;
+        Ok(())
+    }
+}
+
+/// Settings used for every generation.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct InferenceParameters {
+    #[serde(flatten)]
+    pub sampler: SamplerParameters,
+    /// Most tokens generated for a single answer
+    pub maximum_token_count: usize,
+    /// Applied when the model is loaded
+    pub context_size: usize,
+    pub thread_count: usize,
+    /// Fixed seed for reproducible outputs, random when `None`
+    pub seed: Option<u64>,
+}
+
+impl Default for InferenceParameters {
+    fn default() -> Self {
+        InferenceParameters {
+            sampler: SamplerParameters::default(),
+            maximum_token_count: 1000,
+            context_size: 2048,
+            thread_count: std::thread::available_parallelism()
+                .map(|threads| threads.get())
+                .unwrap_or(8),
+            seed: None,
+        }
+    }
+}
+
+impl InferenceParameters {
+    pub fn validate(&self) -> Result<()> {
+        self.sampler.validate()?;
+        check_range("Context size", self.context_size as f64, 128.0, 32768.0)?;
+        check_range(
+            "Max tokens",
+            self.maximum_token_count as f64,
+            1.0,
+            (self.context_size - MINIMUM_PROMPT_TOKENS) as f64,
+        )?;
+        check_range("Threads", self.thread_count as f64, 1.0, 256.0)?;
+        Ok(())
+    }
+}
+
+fn check_range(name: &'static str, value: f64, min: f64, max: f64) -> Result<()> {
+    if !(min..=max).contains(&value) {
+        return Err(Error::OutOfRange {
+            name,
+            min,
+            max,
+            value,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn out_of_range(result: Result<()>) -> &'static str {
+        match result {
+            Err(Error::OutOfRange { name, .. }) => name,
+            Ok(_) => panic!("the parameters should be out of range"),
+        }
+    }
+
+    #[test]
+    fn accepts_the_defaults_and_the_bounds() {
+        assert!(InferenceParameters::default().validate().is_ok());
+        let parameters = InferenceParameters {
+            sampler: SamplerParameters {
+                temperature: 2.0,
+                top_k: 1,
+                min_p: 0.0,
+                ..Default::default()
+            },
+            context_size: 128,
+            maximum_token_count: 128 - MINIMUM_PROMPT_TOKENS,
+            ..Default::default()
+        };
+        assert!(parameters.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_values_out_of_range() {
+        let sampler = |sampler: SamplerParameters| InferenceParameters {
+            sampler,
+            ..Default::default()
+        };
+        assert_eq!(
+            out_of_range(
+                sampler(SamplerParameters {
+                    temperature: 2.5,
+                    ..Default::default()
+                })
+                .validate()
+            ),
+            "Temperature"
+        );
+        assert_eq!(
+            out_of_range(
+                sampler(SamplerParameters {
+                    top_p: 0.0,
+                    ..Default::default()
+                })
+                .validate()
+            ),
+            "Top P"
+        );
+        assert_eq!(
+            out_of_range(
+                InferenceParameters {
+                    thread_count: 0,
+                    ..Default::default()
+                }
+                .validate()
+            ),
+            "Threads"
+        );
+    }
+
+    #[test]
+    fn leaves_room_for_the_prompt() {
+        let parameters = InferenceParameters {
+            context_size: 2048,
+            maximum_token_count: 2048,
+            ..Default::default()
+        };
+        assert_eq!(out_of_range(parameters.validate()), "Max tokens");
+
+        // Checked before max tokens, which would underflow
+        let parameters = InferenceParameters {
+            context_size: 32,
+            maximum_token_count: 16,
+            ..Default::default()
+        };
+        assert_eq!(out_of_range(parameters.validate()), "Context size");
+    }
+
+    #[test]
+    fn rejects_nan() {
+        let parameters = InferenceParameters {
+            sampler: SamplerParameters {
+                temperature: f32::NAN,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(out_of_range(parameters.validate()), "Temperature");
+    }
+
+    #[test]
+    fn rejects_strings_that_are_not_numbers() {
+        let parameters = serde_json::from_value::<InferenceParameters>(
+            serde_json::json!({ "temperature": "abc" }),
+        );
+        assert!(parameters.is_err());
+        let parameters: InferenceParameters =
+            serde_json::from_value(serde_json::json!({ "temperature": 0.5 })).unwrap();
+        assert_eq!(parameters.sampler.temperature, 0.5);
+    }
+}
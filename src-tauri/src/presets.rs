@@ -0,0 +1,204 @@
+use std::fs;
+
+use crate::parameters::SamplerParameters;
+use crate::profiles::ProfileUpdate;
+use crate::prompt_template::ChatTemplate;
+use crate::{configs, localstore, profiles};
+
+/// Named sampler settings, optionally with the templates that go with them.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Preset {
+    pub name: String,
+    pub parameters: SamplerParameters,
+    #[serde(default)]
+    pub prompt_template: Option<String>,
+    #[serde(default)]
+    pub chat_template: Option<ChatTemplate>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct PresetEntry {
+    #[serde(flatten)]
+    pub preset: Preset,
+    /// Shipped in `configs/presets.json`, can't be renamed or deleted
+    pub built_in: bool,
+}
+
+#[tauri::command]
+pub fn get_presets(app_handle: tauri::AppHandle) -> Vec<PresetEntry> {
+    println!("Command: get_presets");
+    return all_presets(&app_handle);
+}
+
+fn all_presets(app_handle: &tauri::AppHandle) -> Vec<PresetEntry> {
+    let mut presets: Vec<PresetEntry> = configs::get_config_presets(app_handle)
+        .into_iter()
+        .map(|preset| PresetEntry {
+            preset,
+            built_in: true,
+        })
+        .collect();
+    presets.extend(
+        localstore::get_presets(app_handle.clone())
+            .into_iter()
+            .map(|preset| PresetEntry {
+                preset,
+                built_in: false,
+            }),
+    );
+    return presets;
+}
+
+/// Saves the parameters of the active model as a new preset.
+#[tauri::command]
+pub fn create_preset(name: String, app_handle: tauri::AppHandle) -> Result<Preset, String> {
+    println!("Command: create_preset, name:{}", name);
+    let profile = profiles::get_effective_profile(&app_handle);
+    let preset = Preset {
+        name,
+        parameters: profile.parameters.value.sampler,
+        prompt_template: Some(profile.prompt_template.value),
+        chat_template: Some(profile.chat_template.value),
+    };
+    add_preset(&app_handle, preset.clone())?;
+    return Ok(preset);
+}
+
+#[tauri::command]
+pub fn duplicate_preset(
+    name: String,
+    new_name: String,
+    app_handle: tauri::AppHandle,
+) -> Result<Preset, String> {
+    println!("Command: duplicate_preset, name:{}", name);
+    let mut preset = find_preset(&app_handle, &name)?;
+    preset.name = new_name;
+    add_preset(&app_handle, preset.clone())?;
+    return Ok(preset);
+}
+
+#[tauri::command]
+pub fn rename_preset(
+    name: String,
+    new_name: String,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    println!("Command: rename_preset, name:{}", name);
+    // Keeping the name is not a clash with the preset itself
+    if new_name != name {
+        check_name_available(&app_handle, &new_name)?;
+    }
+    let mut presets = localstore::get_presets(app_handle.clone());
+    match presets.iter_mut().find(|preset| preset.name == name) {
+        Some(preset) => preset.name = new_name,
+        None => return Err(format!("Preset {} can't be renamed", name)),
+    }
+    return localstore::save_presets(app_handle, &presets);
+}
+
+#[tauri::command]
+pub fn delete_preset(name: String, app_handle: tauri::AppHandle) -> Result<(), String> {
+    println!("Command: delete_preset, name:{}", name);
+    let mut presets = localstore::get_presets(app_handle.clone());
+    let count = presets.len();
+    presets.retain(|preset| preset.name != name);
+    if presets.len() == count {
+        return Err(format!("Preset {} can't be deleted", name));
+    }
+    return localstore::save_presets(app_handle, &presets);
+}
+
+/// Applies a preset to the parameters of the active model. Settings that are
+/// not part of a preset, like the context size, are kept.
+#[tauri::command]
+pub fn apply_preset(name: String, app_handle: tauri::AppHandle) -> Result<(), String> {
+    println!("Command: apply_preset, name:{}", name);
+    let preset = find_preset(&app_handle, &name)?;
+    let mut parameters = profiles::get_effective_profile(&app_handle)
+        .parameters
+        .value;
+    parameters.sampler = preset.parameters;
+
+    return profiles::save_profile(
+        &app_handle,
+        ProfileUpdate {
+            prompt_template: preset.prompt_template,
+            chat_template: preset.chat_template,
+            parameters: Some(parameters),
+            ..Default::default()
+        },
+    );
+}
+
+#[tauri::command]
+pub async fn export_preset(name: String, app_handle: tauri::AppHandle) -> Result<String, String> {
+    use tauri::api::dialog::blocking::FileDialogBuilder;
+
+    let preset = find_preset(&app_handle, &name)?;
+    let dialog_result = FileDialogBuilder::new()
+        .set_file_name(&format!("{}.json", name))
+        .add_filter("JSON", &["json"])
+        .save_file();
+
+    match dialog_result {
+        Some(path) => {
+            let json = serde_json::to_string_pretty(&preset).map_err(|err| err.to_string())?;
+            fs::write(&path, json).map_err(|err| err.to_string())?;
+            return Ok(path.to_string_lossy().to_string());
+        }
+        None => {
+            return Err("No path selected".to_string());
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn import_preset(app_handle: tauri::AppHandle) -> Result<Preset, String> {
+    use tauri::api::dialog::blocking::FileDialogBuilder;
+
+    let dialog_result = FileDialogBuilder::new()
+        .add_filter("JSON", &["json"])
+        .pick_file();
+
+    match dialog_result {
+        Some(path) => {
+            let json = fs::read_to_string(&path).map_err(|err| err.to_string())?;
+            let preset: Preset = serde_json::from_str(&json)
+                .map_err(|err| format!("Not a valid preset file: {}", err))?;
+            add_preset(&app_handle, preset.clone())?;
+            return Ok(preset);
+        }
+        None => {
+            return Err("No file selected".to_string());
+        }
+    }
+}
+
+fn find_preset(app_handle: &tauri::AppHandle, name: &str) -> Result<Preset, String> {
+    return all_presets(app_handle)
+        .into_iter()
+        .map(|entry| entry.preset)
+        .find(|preset| preset.name == name)
+        .ok_or_else(|| format!("Preset {} not found", name));
+}
+
+fn add_preset(app_handle: &tauri::AppHandle, preset: Preset) -> Result<(), String> {
+    check_name_available(app_handle, &preset.name)?;
+    preset
+        .parameters
+        .validate()
+        .map_err(|err| err.to_string())?;
+    let mut presets = localstore::get_presets(app_handle.clone());
+    presets.push(preset);
+    return localstore::save_presets(app_handle.clone(), &presets);
+}
+
+fn check_name_available(app_handle: &tauri::AppHandle, name: &str) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("The preset needs a name".to_string());
+    }
+    if find_preset(app_handle, name).is_ok() {
+        return Err(format!("There is already a preset called {}", name));
+    }
+    Ok(())
+}
@@ -0,0 +1,199 @@
+use crate::configs::{self, ConfigLanguageModel};
+use crate::localstore;
+use crate::parameters::InferenceParameters;
+use crate::prompt_template::ChatTemplate;
+
+/// Parameters saved for a single model, keyed by its filename. Anything left
+/// as `None` falls back to the catalog defaults and then to the global values.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ParameterProfile {
+    pub prompt_template: Option<String>,
+    pub chat_template: Option<ChatTemplate>,
+    pub parameters: Option<InferenceParameters>,
+    pub stop_sequences: Option<Vec<String>>,
+}
+
+/// Values to change in a profile. Fields left as `None` keep what the
+/// profile has.
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ProfileUpdate {
+    pub prompt_template: Option<String>,
+    pub chat_template: Option<ChatTemplate>,
+    pub parameters: Option<InferenceParameters>,
+    /// An empty list goes back to the catalog or global stop sequences
+    pub stop_sequences: Option<Vec<String>>,
+}
+
+impl ProfileUpdate {
+    fn apply(self, profile: &mut ParameterProfile) {
+        if self.prompt_template.is_some() {
+            profile.prompt_template = self.prompt_template;
+        }
+        if self.chat_template.is_some() {
+            profile.chat_template = self.chat_template;
+        }
+        if self.parameters.is_some() {
+            profile.parameters = self.parameters;
+        }
+        if let Some(stop_sequences) = self.stop_sequences {
+            profile.stop_sequences = Some(stop_sequences).filter(|sequences| !sequences.is_empty());
+        }
+    }
+}
+
+/// Where an effective value comes from.
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ParameterSource {
+    /// Saved for the active model
+    Model,
+    /// Defaults for the active model in `configs/models.json`
+    Catalog,
+    /// Saved without a model, or the built-in defaults
+    Global,
+}
+
+pub struct Effective<T> {
+    pub value: T,
+    pub source: ParameterSource,
+}
+
+/// Values used for a model, resolved from its profile, the catalog and the
+/// global parameters, in that order.
+pub struct EffectiveProfile {
+    pub prompt_template: Effective<String>,
+    pub chat_template: Effective<ChatTemplate>,
+    pub parameters: Effective<InferenceParameters>,
+    pub stop_sequences: Effective<Vec<String>>,
+}
+
+pub fn get_effective_profile(app_handle: &tauri::AppHandle) -> EffectiveProfile {
+    let model_filename = localstore::get_current_model_filename(app_handle.clone());
+    return get_effective_profile_for(app_handle, &model_filename);
+}
+
+pub fn get_effective_profile_for(
+    app_handle: &tauri::AppHandle,
+    model_filename: &str,
+) -> EffectiveProfile {
+    let profile =
+        localstore::get_parameter_profile(app_handle.clone(), model_filename).unwrap_or_default();
+    let catalog_model = find_catalog_model(app_handle, model_filename);
+
+    let prompt_template = resolve(
+        profile.prompt_template,
+        catalog_model
+            .as_ref()
+            .map(|catalog_model| catalog_model.prompt_template.clone()),
+        || localstore::get_prompt_template(app_handle.clone()),
+    );
+    // Without a chat template of its own, the chat follows the prompt
+    // template that applies to the model
+    let global_chat_template = match prompt_template.source {
+        ParameterSource::Global => localstore::get_chat_template(app_handle.clone()),
+        _ => None,
+    };
+    let has_global_chat_template = global_chat_template.is_some();
+    let mut chat_template = resolve(
+        profile.chat_template,
+        catalog_model
+            .as_ref()
+            .and_then(|catalog_model| catalog_model.chat_template.clone()),
+        || global_chat_template.unwrap_or_default(),
+    );
+    if chat_template.source == ParameterSource::Global && !has_global_chat_template {
+        chat_template = Effective {
+            value: ChatTemplate::from_prompt_template(&prompt_template.value),
+            source: prompt_template.source,
+        };
+    }
+    let parameters = resolve(
+        profile.parameters,
+        catalog_model
+            .as_ref()
+            .and_then(|catalog_model| catalog_model.parameters.clone()),
+        || localstore::get_parameters(app_handle.clone()),
+    );
+    let stop_sequences = resolve(
+        profile.stop_sequences,
+        catalog_model
+            .as_ref()
+            .map(|catalog_model| catalog_model.stop_sequences.clone())
+            .filter(|stop_sequences| !stop_sequences.is_empty()),
+        || localstore::get_stop_sequences(app_handle.clone()).unwrap_or_default(),
+    );
+
+    return EffectiveProfile {
+        prompt_template,
+        chat_template,
+        parameters,
+        stop_sequences,
+    };
+}
+
+/// Saves into the profile of the active model, or into the global values
+/// when there is no model.
+pub fn save_profile(app_handle: &tauri::AppHandle, update: ProfileUpdate) -> Result<(), String> {
+    let model_filename = localstore::get_current_model_filename(app_handle.clone());
+    if model_filename.is_empty() {
+        return save_global(app_handle, update);
+    }
+
+    let mut profile =
+        localstore::get_parameter_profile(app_handle.clone(), &model_filename).unwrap_or_default();
+    update.apply(&mut profile);
+    return localstore::save_parameter_profile(app_handle.clone(), &model_filename, &profile);
+}
+
+fn save_global(app_handle: &tauri::AppHandle, update: ProfileUpdate) -> Result<(), String> {
+    if let Some(prompt_template) = update.prompt_template {
+        localstore::save_prompt_template(app_handle.clone(), &prompt_template)?;
+    }
+    if let Some(chat_template) = update.chat_template {
+        localstore::save_chat_template(app_handle.clone(), &chat_template)?;
+    }
+    if let Some(parameters) = update.parameters {
+        localstore::save_parameters(app_handle.clone(), &parameters)?;
+    }
+    if let Some(stop_sequences) = update.stop_sequences {
+        localstore::save_stop_sequences(
+            app_handle.clone(),
+            Some(stop_sequences).filter(|sequences| !sequences.is_empty()),
+        )?;
+    }
+    Ok(())
+}
+
+pub(crate) fn find_catalog_model(
+    app_handle: &tauri::AppHandle,
+    model_filename: &str,
+) -> Option<ConfigLanguageModel> {
+    return configs::get_config_language_models(app_handle)
+        .into_iter()
+        .find(|config_model| config_model.filename == model_filename);
+}
+
+fn resolve<T>(
+    profile_value: Option<T>,
+    catalog_value: Option<T>,
+    global_value: impl FnOnce() -> T,
+) -> Effective<T> {
+    if let Some(value) = profile_value {
+        return Effective {
+            value,
+            source: ParameterSource::Model,
+        };
+    }
+    if let Some(value) = catalog_value {
+        return Effective {
+            value,
+            source: ParameterSource::Catalog,
+        };
+    }
+    return Effective {
+        value: global_value(),
+        source: ParameterSource::Global,
+    };
+}
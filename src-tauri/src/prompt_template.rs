@@ -0,0 +1,213 @@
+use crate::conversation::{Message, MessageRole};
+
+/// Describes how a conversation is written for a model.
+///
+/// `system` wraps `system_prompt` through the `[[system]]` placeholder and is
+/// written once at the top. `user_turn` and `assistant_turn` wrap every
+/// message through `[[message]]`. The prompt ends with `generation_prefix`,
+/// which should be the text `assistant_turn` has before `[[message]]`, so a
+/// generated answer reads exactly like an assistant turn in the history.
+/// `bos` is written at the very beginning and `eos` right after the text of
+/// every assistant message.
+///
+/// Some well known formats:
+/// - Vicuna: `user_turn: "USER: [[message]]\n"`,
+///   `assistant_turn: "ASSISTANT:[[message]]\n"`, `generation_prefix: "ASSISTANT:"`
+/// - Alpaca: `user_turn: "### Instruction:\n[[message]]\n\n"`,
+///   `assistant_turn: "### Response:\n[[message]]\n\n"`, `generation_prefix: "### Response:\n"`
+/// - ChatML: `system: "<|im_start|>system\n[[system]]<|im_end|>\n"`,
+///   `user_turn: "<|im_start|>user\n[[message]]<|im_end|>\n"`,
+///   `assistant_turn: "<|im_start|>assistant\n[[message]]<|im_end|>\n"`,
+///   `generation_prefix: "<|im_start|>assistant\n"`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ChatTemplate {
+    pub system_prompt: String,
+    pub system: String,
+    pub user_turn: String,
+    pub assistant_turn: String,
+    pub generation_prefix: String,
+    pub bos: String,
+    pub eos: String,
+}
+
+impl Default for ChatTemplate {
+    fn default() -> Self {
+        ChatTemplate {
+            system_prompt: "".to_string(),
+            system: "[[system]]".to_string(),
+            user_turn: "[[message]]".to_string(),
+            assistant_turn: "[[message]]\n".to_string(),
+            generation_prefix: "".to_string(),
+            bos: "".to_string(),
+            eos: "".to_string(),
+        }
+    }
+}
+
+impl ChatTemplate {
+    /// Builds a chat template out of a single `[[message]]` prompt template.
+    ///
+    /// Everything before the line holding `[[message]]` becomes the system
+    /// prompt and the rest of the template wraps every human turn.
+    pub fn from_prompt_template(prompt_template: &str) -> Self {
+        let (system_prompt, user_turn) = match prompt_template.find("[[message]]") {
+            Some(index) => {
+                let line_start = prompt_template[..index]
+                    .rfind('\n')
+                    .map(|position| position + 1)
+                    .unwrap_or(0);
+                prompt_template.split_at(line_start)
+            }
+            None => ("", "[[message]]"),
+        };
+
+        ChatTemplate {
+            system_prompt: system_prompt.to_string(),
+            user_turn: user_turn.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Renders the conversation, ending with the generation prefix so the
+    /// model continues as the assistant.
+    pub fn render(&self, messages: &[Message]) -> String {
+        let mut prompt = self.bos.clone();
+        if !self.system_prompt.is_empty() {
+            prompt.push_str(&self.system.replace("[[system]]", &self.system_prompt));
+        }
+        for message in messages.iter() {
+            match message.role {
+                MessageRole::Human => {
+                    prompt.push_str(&self.user_turn.replace("[[message]]", &message.text));
+                }
+                MessageRole::AI => {
+                    let text = format!("{}{}", message.text, self.eos);
+                    prompt.push_str(&self.assistant_turn.replace("[[message]]", &text));
+                }
+            }
+        }
+        prompt.push_str(&self.generation_prefix);
+        return prompt;
+    }
+
+    /// Renders the conversation with its last message, an assistant reply,
+    /// left open so the model continues that reply.
+    pub fn render_continuation(&self, messages: &[Message]) -> String {
+        match messages.split_last() {
+            Some((last, previous)) => {
+                return format!("{}{}", self.render(previous), last.text);
+            }
+            None => return self.render(messages),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(turns: &[(MessageRole, &str)]) -> Vec<Message> {
+        return turns
+            .iter()
+            .enumerate()
+            .map(|(index, (role, text))| Message {
+                id: index as u64,
+                parent: (index as u64).checked_sub(1),
+                text: text.to_string(),
+                role: role.clone(),
+            })
+            .collect();
+    }
+
+    fn vicuna() -> ChatTemplate {
+        return ChatTemplate {
+            system_prompt: "A chat with an assistant.".to_string(),
+            system: "[[system]]\n\n".to_string(),
+            user_turn: "USER: [[message]]\n".to_string(),
+            assistant_turn: "ASSISTANT:[[message]]\n".to_string(),
+            generation_prefix: "ASSISTANT:".to_string(),
+            bos: "<s>".to_string(),
+            eos: "</s>".to_string(),
+        };
+    }
+
+    fn chatml() -> ChatTemplate {
+        return ChatTemplate {
+            system_prompt: "You are helpful.".to_string(),
+            system: "<|im_start|>system\n[[system]]<|im_end|>\n".to_string(),
+            user_turn: "<|im_start|>user\n[[message]]<|im_end|>\n".to_string(),
+            assistant_turn: "<|im_start|>assistant\n[[message]]<|im_end|>\n".to_string(),
+            generation_prefix: "<|im_start|>assistant\n".to_string(),
+            bos: "".to_string(),
+            eos: "".to_string(),
+        };
+    }
+
+    #[test]
+    fn renders_vicuna() {
+        let conversation = messages(&[
+            (MessageRole::Human, "Hi"),
+            (MessageRole::AI, "Hello!"),
+            (MessageRole::Human, "How are you?"),
+        ]);
+        assert_eq!(
+            vicuna().render(&conversation),
+            "<s>A chat with an assistant.\n\nUSER: Hi\nASSISTANT:Hello!</s>\n\
+             USER: How are you?\nASSISTANT:"
+        );
+    }
+
+    #[test]
+    fn renders_chatml() {
+        let conversation = messages(&[(MessageRole::Human, "Hi"), (MessageRole::AI, "Hello!")]);
+        assert_eq!(
+            chatml().render(&conversation[..1]),
+            "<|im_start|>system\nYou are helpful.<|im_end|>\n\
+             <|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+        );
+        // The reply left open ends like the generation prefix did
+        assert_eq!(
+            chatml().render_continuation(&conversation),
+            "<|im_start|>system\nYou are helpful.<|im_end|>\n\
+             <|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\nHello!"
+        );
+
+        let without_system = ChatTemplate {
+            system_prompt: "".to_string(),
+            ..chatml()
+        };
+        assert_eq!(
+            without_system.render(&conversation[..1]),
+            "<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert_eq!(
+            without_system.render_continuation(&[]),
+            "<|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn splits_prompt_templates_at_the_message_line() {
+        let template = ChatTemplate::from_prompt_template(
+            "You are helpful.\n### Instruction:\n[[message]]\n### Response:\n",
+        );
+        assert_eq!(
+            template.system_prompt,
+            "You are helpful.\n### Instruction:\n"
+        );
+        assert_eq!(template.user_turn, "[[message]]\n### Response:\n");
+        assert_eq!(
+            template.render(&messages(&[(MessageRole::Human, "Hi")])),
+            "You are helpful.\n### Instruction:\nHi\n### Response:\n"
+        );
+
+        let first_line = ChatTemplate::from_prompt_template("Q: [[message]]\nA:");
+        assert_eq!(first_line.system_prompt, "");
+        assert_eq!(first_line.user_turn, "Q: [[message]]\nA:");
+
+        let no_placeholder = ChatTemplate::from_prompt_template("Just text");
+        assert_eq!(no_placeholder.system_prompt, "");
+        assert_eq!(no_placeholder.user_turn, "[[message]]");
+    }
+}
@@ -0,0 +1,610 @@
+use std::cmp::Ordering;
+use std::sync::{Arc, Mutex};
+
+use llm::samplers::Sampler;
+use llm::TokenId;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::RngCore;
+
+use crate::grammar::{Grammar, GrammarMatcher};
+use crate::parameters::{SamplerParameters, SamplerStrategy};
+
+/// Builds the sampler selected in the parameters.
+pub fn build_sampler(parameters: &SamplerParameters) -> Arc<dyn Sampler> {
+    let penalty = Penalty {
+        temperature: parameters.temperature,
+        repeat_penalty: parameters.repetition_penalty,
+        last_n: parameters.repetition_penalty_last_n,
+    };
+    return match parameters.strategy {
+        SamplerStrategy::TopPTopK => Arc::new(llm::samplers::TopPTopK {
+            top_p: parameters.top_p,
+            top_k: parameters.top_k,
+            repeat_penalty: parameters.repetition_penalty,
+            temperature: parameters.temperature,
+            repetition_penalty_last_n: parameters.repetition_penalty_last_n,
+            ..Default::default()
+        }),
+        SamplerStrategy::Mirostat => Arc::new(Mirostat::new(
+            MirostatVersion::V1,
+            parameters.mirostat_tau,
+            parameters.mirostat_eta,
+            penalty,
+        )),
+        SamplerStrategy::MirostatV2 => Arc::new(Mirostat::new(
+            MirostatVersion::V2,
+            parameters.mirostat_tau,
+            parameters.mirostat_eta,
+            penalty,
+        )),
+        SamplerStrategy::Typical => Arc::new(LocallyTypical {
+            p: parameters.typical_p,
+            penalty,
+        }),
+        SamplerStrategy::TailFree => Arc::new(TailFree {
+            z: parameters.tail_free_z,
+            penalty,
+        }),
+        SamplerStrategy::MinP => Arc::new(MinP {
+            p: parameters.min_p,
+            penalty,
+        }),
+    };
+}
+
+/// Temperature and repeat penalty, applied to the logits before any of the
+/// samplers below cut down the candidates.
+#[derive(Debug, Clone, Copy)]
+struct Penalty {
+    temperature: f32,
+    repeat_penalty: f32,
+    last_n: usize,
+}
+
+impl Penalty {
+    /// Returns the candidates sorted from the most to the least likely, with
+    /// their probabilities.
+    fn candidates(&self, previous_tokens: &[TokenId], logits: &[f32]) -> Vec<(TokenId, f32)> {
+        let recent_tokens = &previous_tokens[previous_tokens.len().saturating_sub(self.last_n)..];
+        let scale = 1.0 / self.temperature;
+        let mut candidates: Vec<(TokenId, f32)> = logits
+            .iter()
+            .enumerate()
+            .map(|(index, &logit)| {
+                let token_id = index as TokenId;
+                let logit = if recent_tokens.contains(&token_id) {
+                    if logit < 0.0 {
+                        logit * self.repeat_penalty
+                    } else {
+                        logit / self.repeat_penalty
+                    }
+                } else {
+                    logit
+                };
+                (token_id, logit * scale)
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+
+        let max_logit = candidates
+            .first()
+            .map(|candidate| candidate.1)
+            .unwrap_or(0.0);
+        for candidate in candidates.iter_mut() {
+            candidate.1 = (candidate.1 - max_logit).exp();
+        }
+        normalize(&mut candidates);
+        return candidates;
+    }
+}
+
+fn normalize(candidates: &mut [(TokenId, f32)]) {
+    let sum: f32 = candidates.iter().map(|candidate| candidate.1).sum();
+    for candidate in candidates.iter_mut() {
+        candidate.1 /= sum;
+    }
+}
+
+/// Picks one of the candidates according to their probabilities. Returns its
+/// position in `candidates`.
+fn pick(candidates: &[(TokenId, f32)], rng: &mut dyn RngCore) -> usize {
+    let weights = candidates.iter().map(|candidate| candidate.1);
+    return match WeightedIndex::new(weights) {
+        Ok(distribution) => distribution.sample(rng),
+        // All weights vanished, fall back to the most likely token
+        Err(_) => 0,
+    };
+}
+
+#[derive(Debug, Clone, Copy)]
+enum MirostatVersion {
+    V1,
+    V2,
+}
+
+/// Mirostat keeps the surprise of the generated text close to `tau`, see
+/// <https://arxiv.org/abs/2007.14966>.
+#[derive(Debug)]
+struct Mirostat {
+    version: MirostatVersion,
+    tau: f32,
+    eta: f32,
+    penalty: Penalty,
+    /// Maximum surprise, learned while generating
+    mu: Mutex<f32>,
+}
+
+impl Mirostat {
+    /// Number of candidates used to estimate the Zipf exponent in v1
+    const M: usize = 100;
+
+    fn new(version: MirostatVersion, tau: f32, eta: f32, penalty: Penalty) -> Self {
+        Mirostat {
+            version,
+            tau,
+            eta,
+            penalty,
+            mu: Mutex::new(2.0 * tau),
+        }
+    }
+}
+
+impl Sampler for Mirostat {
+    fn sample(
+        &self,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn RngCore,
+    ) -> TokenId {
+        let mut candidates = self.penalty.candidates(previous_tokens, logits);
+        let mut mu = self.mu.lock().unwrap();
+
+        match self.version {
+            MirostatVersion::V1 => {
+                let mut sum_ti_bi = 0.0;
+                let mut sum_ti_sq = 0.0;
+                for (i, pair) in candidates.windows(2).take(Self::M - 1).enumerate() {
+                    let t_i = ((i + 2) as f32 / (i + 1) as f32).ln();
+                    let b_i = (pair[0].1 / pair[1].1).ln();
+                    sum_ti_bi += t_i * b_i;
+                    sum_ti_sq += t_i * t_i;
+                }
+                let s_hat = sum_ti_bi / sum_ti_sq;
+                let epsilon_hat = s_hat - 1.0;
+                let n = candidates.len() as f32;
+                let k = ((epsilon_hat * 2f32.powf(*mu)) / (1.0 - n.powf(-epsilon_hat)))
+                    .powf(1.0 / s_hat);
+                let k = if k.is_finite() { k as usize } else { 1 };
+                candidates.truncate(k.clamp(1, candidates.len()));
+            }
+            MirostatVersion::V2 => {
+                let kept = candidates
+                    .iter()
+                    .position(|candidate| -candidate.1.log2() > *mu)
+                    .unwrap_or(candidates.len());
+                candidates.truncate(kept.max(1));
+            }
+        }
+        normalize(&mut candidates);
+
+        let (token_id, probability) = candidates[pick(&candidates, rng)];
+        let observed_surprise = -probability.log2();
+        *mu -= self.eta * (observed_surprise - self.tau);
+        return token_id;
+    }
+}
+
+/// Keeps the tokens whose surprise is closest to the entropy of the
+/// distribution, see <https://arxiv.org/abs/2202.00666>.
+#[derive(Debug)]
+struct LocallyTypical {
+    p: f32,
+    penalty: Penalty,
+}
+
+impl Sampler for LocallyTypical {
+    fn sample(
+        &self,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn RngCore,
+    ) -> TokenId {
+        let candidates = self.penalty.candidates(previous_tokens, logits);
+        let entropy: f32 = candidates
+            .iter()
+            .filter(|candidate| candidate.1 > 0.0)
+            .map(|candidate| -candidate.1 * candidate.1.ln())
+            .sum();
+
+        let mut by_typicality: Vec<(f32, (TokenId, f32))> = candidates
+            .into_iter()
+            .map(|candidate| ((-candidate.1.ln() - entropy).abs(), candidate))
+            .collect();
+        by_typicality.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+
+        let mut cumulative = 0.0;
+        let mut kept = Vec::new();
+        for (_, candidate) in by_typicality {
+            cumulative += candidate.1;
+            kept.push(candidate);
+            if cumulative >= self.p {
+                break;
+            }
+        }
+        return kept[pick(&kept, rng)].0;
+    }
+}
+
+/// Cuts the tail of the distribution where the second derivative of the
+/// sorted probabilities flattens out, see
+/// <https://www.trentonbricken.com/Tail-Free-Sampling/>.
+#[derive(Debug)]
+struct TailFree {
+    z: f32,
+    penalty: Penalty,
+}
+
+impl Sampler for TailFree {
+    fn sample(
+        &self,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn RngCore,
+    ) -> TokenId {
+        let mut candidates = self.penalty.candidates(previous_tokens, logits);
+        candidates.truncate(tail_free_count(&candidates, self.z));
+        return candidates[pick(&candidates, rng)].0;
+    }
+}
+
+/// How many of the sorted candidates tail-free sampling keeps. As in
+/// llama.cpp, the cut is at the index where the cumulative second derivative
+/// passes `z`, and at least one candidate is kept.
+fn tail_free_count(candidates: &[(TokenId, f32)], z: f32) -> usize {
+    if candidates.len() <= 2 {
+        return candidates.len();
+    }
+    let first_derivatives: Vec<f32> = candidates
+        .windows(2)
+        .map(|pair| pair[0].1 - pair[1].1)
+        .collect();
+    let second_derivatives: Vec<f32> = first_derivatives
+        .windows(2)
+        .map(|pair| (pair[0] - pair[1]).abs())
+        .collect();
+    let sum: f32 = second_derivatives.iter().sum();
+    if sum <= 0.0 {
+        return candidates.len();
+    }
+
+    let mut cumulative = 0.0;
+    for (index, second_derivative) in second_derivatives.iter().enumerate() {
+        cumulative += second_derivative / sum;
+        if cumulative > z && index >= 1 {
+            return index;
+        }
+    }
+    return candidates.len();
+}
+
+/// Keeps the tokens at least `p` times as likely as the most likely one.
+#[derive(Debug)]
+struct MinP {
+    p: f32,
+    penalty: Penalty,
+}
+
+impl Sampler for MinP {
+    fn sample(
+        &self,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn RngCore,
+    ) -> TokenId {
+        let mut candidates = self.penalty.candidates(previous_tokens, logits);
+        let threshold = candidates[0].1 * self.p;
+        candidates.retain(|candidate| candidate.1 >= threshold);
+        return candidates[pick(&candidates, rng)].0;
+    }
+}
+
+/// Restricts another sampler to the tokens a grammar allows next. The end of
+/// text token is only allowed once the grammar is complete.
+#[derive(Debug)]
+pub struct GrammarSampler {
+    inner: Arc<dyn Sampler>,
+    matcher: Mutex<GrammarMatcher>,
+    /// Text of every token, `None` for tokens that aren't valid UTF-8 on
+    /// their own
+    tokens: Vec<Option<String>>,
+    end_of_text: TokenId,
+}
+
+impl GrammarSampler {
+    pub fn new(inner: Arc<dyn Sampler>, grammar: Arc<Grammar>, model: &dyn llm::Model) -> Self {
+        let vocabulary = model.vocabulary();
+        let tokens = (0..vocabulary.len())
+            .map(|token_id| String::from_utf8(vocabulary.token(token_id).to_vec()).ok())
+            .collect();
+        GrammarSampler {
+            inner,
+            matcher: Mutex::new(GrammarMatcher::new(grammar)),
+            tokens,
+            end_of_text: model.eot_token_id(),
+        }
+    }
+}
+
+impl Sampler for GrammarSampler {
+    fn sample(
+        &self,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn RngCore,
+    ) -> TokenId {
+        let mut matcher = self.matcher.lock().unwrap();
+        let mut masked_logits = logits.to_vec();
+        let mut any_allowed = false;
+        for (token_id, logit) in masked_logits.iter_mut().enumerate() {
+            let allowed = if token_id as TokenId == self.end_of_text {
+                matcher.is_complete()
+            } else {
+                match self.tokens.get(token_id) {
+                    Some(Some(text)) => !text.is_empty() && matcher.accepts(text),
+                    _ => false,
+                }
+            };
+            if allowed {
+                any_allowed = true;
+            } else {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+        // No token can continue the grammar, end the text
+        if !any_allowed {
+            return self.end_of_text;
+        }
+
+        let token_id = self.inner.sample(previous_tokens, &masked_logits, rng);
+        if token_id != self.end_of_text {
+            if let Some(Some(text)) = self.tokens.get(token_id as usize) {
+                matcher.advance(text);
+            }
+        }
+        return token_id;
+    }
+}
+
+/// A sampled token with its log-probability and the most likely tokens at
+/// that step, all taken from the logits before any sampler changed them.
+#[derive(Debug, Clone)]
+pub struct SampledToken {
+    pub token_id: TokenId,
+    pub logprob: f32,
+    pub alternatives: Vec<(TokenId, f32)>,
+}
+
+/// Records every token another sampler picks, see [`SampledToken`].
+#[derive(Debug)]
+pub struct ProbabilitySampler {
+    inner: Arc<dyn Sampler>,
+    alternative_count: usize,
+    sampled_tokens: Arc<Mutex<Vec<SampledToken>>>,
+}
+
+impl ProbabilitySampler {
+    pub fn new(
+        inner: Arc<dyn Sampler>,
+        alternative_count: usize,
+        sampled_tokens: Arc<Mutex<Vec<SampledToken>>>,
+    ) -> Self {
+        ProbabilitySampler {
+            inner,
+            alternative_count,
+            sampled_tokens,
+        }
+    }
+}
+
+impl Sampler for ProbabilitySampler {
+    fn sample(
+        &self,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn RngCore,
+    ) -> TokenId {
+        let token_id = self.inner.sample(previous_tokens, logits, rng);
+
+        let max_logit = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let log_sum = logits
+            .iter()
+            .map(|logit| (logit - max_logit).exp())
+            .sum::<f32>()
+            .ln()
+            + max_logit;
+        let logprob = |token_id: TokenId| logits[token_id as usize] - log_sum;
+
+        let mut alternatives: Vec<TokenId> = (0..logits.len() as TokenId).collect();
+        let count = self.alternative_count.min(alternatives.len());
+        if count > 0 {
+            let by_logit = |a: &TokenId, b: &TokenId| {
+                logits[*b as usize]
+                    .partial_cmp(&logits[*a as usize])
+                    .unwrap_or(Ordering::Equal)
+            };
+            alternatives.select_nth_unstable_by(count - 1, by_logit);
+            alternatives.truncate(count);
+            alternatives.sort_by(by_logit);
+        } else {
+            alternatives.clear();
+        }
+
+        self.sampled_tokens.lock().unwrap().push(SampledToken {
+            token_id,
+            logprob: logprob(token_id),
+            alternatives: alternatives
+                .into_iter()
+                .map(|alternative| (alternative, logprob(alternative)))
+                .collect(),
+        });
+        return token_id;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    const NO_PENALTY: Penalty = Penalty {
+        temperature: 1.0,
+        repeat_penalty: 1.0,
+        last_n: 0,
+    };
+
+    fn candidates(probabilities: &[f32]) -> Vec<(TokenId, f32)> {
+        return probabilities
+            .iter()
+            .enumerate()
+            .map(|(index, &probability)| (index as TokenId, probability))
+            .collect();
+    }
+
+    #[test]
+    fn tail_free_cuts_where_the_second_derivative_passes_z() {
+        // First derivatives 0.25, 0.10, 0.09, 0.02, second derivatives 0.15,
+        // 0.01, 0.07, so the cumulative normalized ones are 0.652, 0.696, 1.0
+        let candidates = candidates(&[0.5, 0.25, 0.15, 0.06, 0.04]);
+        assert_eq!(tail_free_count(&candidates, 0.9), 2);
+        assert_eq!(tail_free_count(&candidates, 0.65), 1);
+        // Passed at the first index, still one candidate is kept
+        assert_eq!(tail_free_count(&candidates, 0.5), 1);
+    }
+
+    #[test]
+    fn tail_free_keeps_a_flat_distribution() {
+        let candidates = candidates(&[0.25, 0.25, 0.25, 0.25]);
+        assert_eq!(tail_free_count(&candidates, 0.5), 4);
+        assert_eq!(tail_free_count(&candidates[..2], 0.5), 2);
+    }
+
+    #[test]
+    fn tail_free_samples_only_the_kept_tokens() {
+        let sampler = TailFree {
+            z: 0.9,
+            penalty: NO_PENALTY,
+        };
+        let logits: Vec<f32> = [0.5f32, 0.25, 0.15, 0.06, 0.04]
+            .iter()
+            .map(|probability| probability.ln())
+            .collect();
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..200 {
+            let token_id = sampler.sample(&[], &logits, &mut rng);
+            assert!(token_id <= 1, "sampled token {}", token_id);
+        }
+    }
+
+    #[test]
+    fn candidates_are_sorted_probabilities_with_the_repeat_penalty() {
+        let penalty = Penalty {
+            temperature: 1.0,
+            repeat_penalty: 2.0,
+            last_n: 1,
+        };
+        let candidates = penalty.candidates(&[0], &[2.0f32.ln() * 2.0, 0.0]);
+        // The repeated token goes from 4 to 2 times as likely as the other
+        assert_eq!(candidates[0].0, 0);
+        assert!((candidates[0].1 - 2.0 / 3.0).abs() < 1e-6);
+        assert!((candidates[1].1 - 1.0 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn min_p_keeps_tokens_relative_to_the_most_likely() {
+        let sampler = MinP {
+            p: 0.5,
+            penalty: NO_PENALTY,
+        };
+        let logits: Vec<f32> = [0.5f32, 0.3, 0.2]
+            .iter()
+            .map(|probability| probability.ln())
+            .collect();
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..200 {
+            assert!(sampler.sample(&[], &logits, &mut rng) <= 1);
+        }
+    }
+
+    fn logits(probabilities: &[f32]) -> Vec<f32> {
+        return probabilities
+            .iter()
+            .map(|probability| probability.ln())
+            .collect();
+    }
+
+    /// Every token the sampler picks in a few hundred tries, sorted.
+    fn sampled_tokens(sampler: &dyn Sampler, logits: &[f32]) -> Vec<TokenId> {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut tokens: Vec<TokenId> = (0..500)
+            .map(|_| sampler.sample(&[], logits, &mut rng))
+            .collect();
+        tokens.sort_unstable();
+        tokens.dedup();
+        return tokens;
+    }
+
+    #[test]
+    fn mirostat_v1_keeps_the_estimated_number_of_tokens() {
+        // Zipf distributed with exponent 2, so epsilon is 1 and
+        // k = (2^mu / (1 - 1/4))^(1/2) with mu starting at twice tau
+        let logits = logits(&[1.0, 1.0 / 4.0, 1.0 / 9.0, 1.0 / 16.0]);
+        let mirostat = |tau| Mirostat::new(MirostatVersion::V1, tau, 0.0, NO_PENALTY);
+        // k = 1.37
+        assert_eq!(sampled_tokens(&mirostat(0.25), &logits), vec![0]);
+        // k = 2.31
+        assert_eq!(sampled_tokens(&mirostat(1.0), &logits), vec![0, 1]);
+        // k = 9.24, as many as there are
+        assert_eq!(sampled_tokens(&mirostat(3.0), &logits), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn mirostat_v2_keeps_the_tokens_less_surprising_than_mu() {
+        // Surprises of 1, 1.74, 2.74 and 4.32 bits, mu starts at 2
+        let logits = logits(&[0.5, 0.3, 0.15, 0.05]);
+        let sampler = Mirostat::new(MirostatVersion::V2, 1.0, 0.0, NO_PENALTY);
+        assert_eq!(sampled_tokens(&sampler, &logits), vec![0, 1]);
+
+        // Even the most likely token is too surprising, it's still kept
+        let sampler = Mirostat::new(MirostatVersion::V2, 0.1, 0.0, NO_PENALTY);
+        assert_eq!(sampled_tokens(&sampler, &logits), vec![0]);
+    }
+
+    #[test]
+    fn mirostat_learns_mu_from_the_observed_surprise() {
+        let sampler = Mirostat::new(MirostatVersion::V2, 1.0, 0.1, NO_PENALTY);
+        let mut rng = StdRng::seed_from_u64(5);
+        let token_id = sampler.sample(&[], &logits(&[0.5, 0.3, 0.15, 0.05]), &mut rng);
+        // Picked among the two kept tokens, renormalized to 0.625 and 0.375
+        let probability = if token_id == 0 { 0.625f32 } else { 0.375 };
+        let expected_mu = 2.0 - 0.1 * (-probability.log2() - 1.0);
+        assert!((*sampler.mu.lock().unwrap() - expected_mu).abs() < 1e-5);
+    }
+
+    #[test]
+    fn locally_typical_keeps_the_tokens_closest_to_the_entropy() {
+        // Entropy 1.28 nats, surprises 0.92, 1.20, 1.61 and 2.30, so from the
+        // most typical: token 1, 2, 0 and 3
+        let logits = logits(&[0.4, 0.3, 0.2, 0.1]);
+        let typical = |p| LocallyTypical {
+            p,
+            penalty: NO_PENALTY,
+        };
+        assert_eq!(sampled_tokens(&typical(0.2), &logits), vec![1]);
+        // The most likely token is left out
+        assert_eq!(sampled_tokens(&typical(0.45), &logits), vec![1, 2]);
+        assert_eq!(sampled_tokens(&typical(0.85), &logits), vec![0, 1, 2]);
+        assert_eq!(sampled_tokens(&typical(1.0), &logits), vec![0, 1, 2, 3]);
+    }
+}
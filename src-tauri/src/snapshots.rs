@@ -0,0 +1,144 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::conversation::Conversation;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Serialization(#[from] bincode::Error),
+    #[error(transparent)]
+    Snapshot(#[from] llm::SnapshotError),
+    #[error("The snapshot was taken with a different model file")]
+    ModelChanged,
+}
+
+const SNAPSHOT_FILENAME: &str = "chat_session.bin";
+
+/// Identifies a model file on disk. A snapshot is only valid for the exact
+/// file it was taken with, so if the file is replaced or re-downloaded the
+/// snapshot is discarded.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub struct ModelFileFingerprint {
+    path: String,
+    size: u64,
+    modified: u64,
+}
+
+impl ModelFileFingerprint {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        Ok(ModelFileFingerprint {
+            path: path.to_string_lossy().to_string(),
+            size: metadata.len(),
+            modified,
+        })
+    }
+}
+
+#[derive(serde::Serialize)]
+struct ChatSnapshotRef<'a> {
+    model_file: ModelFileFingerprint,
+    key: &'a str,
+    transcript: &'a str,
+    conversation: &'a Conversation,
+    snapshot: llm::InferenceSnapshotRef<'a>,
+}
+
+#[derive(serde::Deserialize)]
+struct ChatSnapshot {
+    model_file: ModelFileFingerprint,
+    key: String,
+    transcript: String,
+    conversation: Conversation,
+    snapshot: llm::InferenceSnapshot,
+}
+
+/// A chat restored from disk, ready to continue.
+pub struct RestoredChat {
+    pub session: llm::InferenceSession,
+    pub key: String,
+    pub transcript: String,
+    pub conversation: Conversation,
+}
+
+pub fn save_chat(
+    app_handle: &tauri::AppHandle,
+    model_path: &Path,
+    session: &llm::InferenceSession,
+    key: &str,
+    transcript: &str,
+    conversation: &Conversation,
+) -> Result<()> {
+    let snapshots_dir = snapshots_dir(app_handle);
+    fs::create_dir_all(&snapshots_dir)?;
+
+    // Safety: the session is borrowed for the whole time the snapshot is alive,
+    // so its memory can't be modified while it is written to disk.
+    let snapshot = unsafe { session.get_snapshot() };
+    let chat_snapshot = ChatSnapshotRef {
+        model_file: ModelFileFingerprint::from_path(model_path)?,
+        key,
+        transcript,
+        conversation,
+        snapshot,
+    };
+
+    let writer = BufWriter::new(File::create(snapshots_dir.join(SNAPSHOT_FILENAME))?);
+    bincode::serialize_into(writer, &chat_snapshot)?;
+    println!("Chat session snapshot saved");
+    Ok(())
+}
+
+pub fn load_chat(
+    app_handle: &tauri::AppHandle,
+    model_path: &Path,
+    model: &dyn llm::Model,
+) -> Result<RestoredChat> {
+    let snapshot_path = snapshots_dir(app_handle).join(SNAPSHOT_FILENAME);
+    let reader = BufReader::new(File::open(&snapshot_path)?);
+    let chat_snapshot: ChatSnapshot = bincode::deserialize_from(reader)?;
+
+    if chat_snapshot.model_file != ModelFileFingerprint::from_path(model_path)? {
+        println!("Model file changed, discarding snapshot");
+        delete_chat(app_handle);
+        return Err(Error::ModelChanged);
+    }
+
+    let session = llm::InferenceSession::from_snapshot(chat_snapshot.snapshot, model)?;
+    println!("Chat session snapshot loaded");
+    Ok(RestoredChat {
+        session,
+        key: chat_snapshot.key,
+        transcript: chat_snapshot.transcript,
+        conversation: chat_snapshot.conversation,
+    })
+}
+
+pub fn delete_chat(app_handle: &tauri::AppHandle) {
+    let snapshot_path = snapshots_dir(app_handle).join(SNAPSHOT_FILENAME);
+    if snapshot_path.exists() {
+        if let Err(err) = fs::remove_file(&snapshot_path) {
+            println!("Error deleting snapshot: {}", err);
+        }
+    }
+}
+
+fn snapshots_dir(app_handle: &tauri::AppHandle) -> PathBuf {
+    return app_handle
+        .path_resolver()
+        .app_data_dir()
+        .unwrap()
+        .join("sessions");
+}
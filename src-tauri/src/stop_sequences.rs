@@ -0,0 +1,140 @@
+/// Watches the generated text for stop sequences.
+///
+/// A stop sequence can be split over several tokens, so text that could be
+/// the beginning of one is held back until it is clear whether it matches.
+pub struct StopSequenceMatcher {
+    stop_sequences: Vec<String>,
+    pending: String,
+}
+
+impl StopSequenceMatcher {
+    pub fn new(stop_sequences: Vec<String>) -> Self {
+        StopSequenceMatcher {
+            stop_sequences: stop_sequences
+                .into_iter()
+                .filter(|stop_sequence| !stop_sequence.is_empty())
+                .collect(),
+            pending: "".to_string(),
+        }
+    }
+
+    /// Adds a token to the generated text. Returns the text that is safe to
+    /// show and whether a stop sequence was found, in which case the stop
+    /// sequence and everything after it is dropped.
+    pub fn push(&mut self, token: &str) -> (String, bool) {
+        self.pending.push_str(token);
+
+        let first_match = self
+            .stop_sequences
+            .iter()
+            .filter_map(|stop_sequence| self.pending.find(stop_sequence.as_str()))
+            .min();
+        if let Some(position) = first_match {
+            let text = self.pending[..position].to_string();
+            self.pending.clear();
+            return (text, true);
+        }
+
+        let held_back = self.partial_match_len();
+        let text = self.pending[..self.pending.len() - held_back].to_string();
+        self.pending.drain(..self.pending.len() - held_back);
+        return (text, false);
+    }
+
+    /// Returns the text held back when the generation ends without a match.
+    pub fn flush(&mut self) -> String {
+        return std::mem::take(&mut self.pending);
+    }
+
+    /// Length of the longest end of the pending text that is the beginning
+    /// of a stop sequence.
+    fn partial_match_len(&self) -> usize {
+        return self
+            .pending
+            .char_indices()
+            .map(|(index, _)| &self.pending[index..])
+            .find(|suffix| {
+                self.stop_sequences
+                    .iter()
+                    .any(|stop_sequence| stop_sequence.starts_with(suffix))
+            })
+            .map(|suffix| suffix.len())
+            .unwrap_or(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_matcher(stop_sequences: &[&str]) -> StopSequenceMatcher {
+        return StopSequenceMatcher::new(
+            stop_sequences
+                .iter()
+                .map(|stop_sequence| stop_sequence.to_string())
+                .collect(),
+        );
+    }
+
+    fn shown(text: &str, found: bool) -> (String, bool) {
+        return (text.to_string(), found);
+    }
+
+    #[test]
+    fn finds_a_stop_sequence_split_over_tokens() {
+        let mut matcher = new_matcher(&["\nUser:"]);
+        assert_eq!(matcher.push("Hello"), shown("Hello", false));
+        assert_eq!(matcher.push("!\nUs"), shown("!", false));
+        assert_eq!(matcher.push("er"), shown("", false));
+        assert_eq!(matcher.push(": next"), shown("", true));
+        assert_eq!(matcher.flush(), "");
+    }
+
+    #[test]
+    fn releases_text_that_stops_matching() {
+        let mut matcher = new_matcher(&["\nUser:"]);
+        assert_eq!(matcher.push("Hi\nU"), shown("Hi", false));
+        assert_eq!(matcher.push("nder"), shown("\nUnder", false));
+        // A new partial match can start inside the released text
+        assert_eq!(matcher.push("\n\nUse"), shown("\n", false));
+        assert_eq!(matcher.push("r:"), shown("", true));
+    }
+
+    #[test]
+    fn finds_a_stop_sequence_at_the_start() {
+        let mut matcher = new_matcher(&["User:"]);
+        assert_eq!(matcher.push("User:"), shown("", true));
+
+        let mut matcher = new_matcher(&["User:"]);
+        assert_eq!(matcher.push("Us"), shown("", false));
+        assert_eq!(matcher.push("er: hi"), shown("", true));
+    }
+
+    #[test]
+    fn stops_at_the_first_of_several_stop_sequences() {
+        let mut matcher = new_matcher(&["###", "</s>"]);
+        assert_eq!(matcher.push("a </s> b ###"), shown("a ", true));
+
+        let mut matcher = new_matcher(&["###", "</s>"]);
+        assert_eq!(matcher.push("done <"), shown("done ", false));
+        assert_eq!(matcher.push("/s"), shown("", false));
+        assert_eq!(matcher.push(">"), shown("", true));
+    }
+
+    #[test]
+    fn flush_returns_the_held_back_text() {
+        let mut matcher = new_matcher(&["\nUser:", "ü!"]);
+        assert_eq!(matcher.push("The end\nUs"), shown("The end", false));
+        assert_eq!(matcher.flush(), "\nUs");
+        assert_eq!(matcher.flush(), "");
+        assert_eq!(matcher.push("Grüß dich ü"), shown("Grüß dich ", false));
+        assert_eq!(matcher.flush(), "ü");
+    }
+
+    #[test]
+    fn ignores_empty_stop_sequences() {
+        let mut matcher = new_matcher(&[""]);
+        assert_eq!(matcher.push("text"), shown("text", false));
+        assert_eq!(matcher.flush(), "");
+    }
+}
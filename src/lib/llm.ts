@@ -1,13 +1,219 @@
 import { invoke } from '@tauri-apps/api';
+import { listen } from '@tauri-apps/api/event';
+import type {
+	Completion,
+	CompletionStats,
+	ConversationSummary,
+	ExportFormat,
+	SearchResult,
+	Message,
+	NewTokenPayload,
+	SubmittedPayload,
+	TokenProbability
+} from './types';
 
-export async function chat(message: string): Promise<string> {
-	return await invoke('chat', { message: message });
+export type TokenCallback = (token: string, probabilities?: TokenProbability[]) => void;
+
+// Resolves to the completion, or rejects with a CommandError. With
+// topLogprobs, every token comes with its probability and that many
+// alternatives.
+export async function chat(
+	message: string,
+	onStart: (requestId: number) => void,
+	onToken: TokenCallback,
+	topLogprobs: number | null = null
+): Promise<Completion> {
+	return await streamRequest(
+		'chat',
+		{ message: message, topLogprobs: topLogprobs },
+		onStart,
+		onToken
+	);
+}
+
+// Answers the last human message again. Only the last reply can be
+// regenerated.
+export async function regenerateMessage(
+	messageId: number,
+	onStart: (requestId: number) => void,
+	onToken: TokenCallback,
+	topLogprobs: number | null = null
+): Promise<Completion> {
+	return await streamRequest(
+		'regenerate_message',
+		{ messageId: messageId, topLogprobs: topLogprobs },
+		onStart,
+		onToken
+	);
+}
+
+// Continues the last reply, the answer is only the text added to it.
+export async function continueMessage(
+	messageId: number,
+	onStart: (requestId: number) => void,
+	onToken: TokenCallback,
+	topLogprobs: number | null = null
+): Promise<Completion> {
+	return await streamRequest(
+		'continue_message',
+		{ messageId: messageId, topLogprobs: topLogprobs },
+		onStart,
+		onToken
+	);
+}
+
+// Edits a human message and answers it. The original and its replies stay
+// as another branch.
+export async function editMessage(
+	messageId: number,
+	text: string,
+	onStart: (requestId: number) => void,
+	onToken: TokenCallback,
+	topLogprobs: number | null = null
+): Promise<Completion> {
+	return await streamRequest(
+		'edit_message',
+		{ messageId: messageId, text: text, topLogprobs: topLogprobs },
+		onStart,
+		onToken
+	);
+}
+
+export async function ask(
+	message: string,
+	onStart: (requestId: number) => void,
+	onToken: TokenCallback,
+	topLogprobs: number | null = null
+): Promise<Completion> {
+	return await streamRequest(
+		'ask',
+		{ message: message, topLogprobs: topLogprobs },
+		onStart,
+		onToken
+	);
+}
+
+// Resolves to the parsed JSON answer, constrained to the grammar (GBNF) or
+// the JSON schema when given, or to any JSON object otherwise.
+export async function askStructured(
+	message: string,
+	options: { schema?: object; grammar?: string } = {}
+): Promise<unknown> {
+	return await invoke('ask_structured', {
+		message: message,
+		schema: options.schema ?? null,
+		grammar: options.grammar ?? null
+	});
+}
+
+export async function cancelInference(requestId: number): Promise<boolean> {
+	return await invoke('cancel_inference', { requestId: requestId });
+}
+
+// Recent stats of a model, oldest first, the current model by default.
+export async function getInferenceStats(
+	modelFilename: string | null = null
+): Promise<CompletionStats[]> {
+	return await invoke('get_inference_stats', { modelFilename: modelFilename });
+}
+
+export async function getChatHistory(): Promise<Message[]> {
+	return await invoke('get_chat_history');
+}
+
+// Starts a new conversation, once the requests already sent are answered.
+export async function newChat(): Promise<void> {
+	await invoke('new_chat');
 }
 
-export async function ask(message: string): Promise<string> {
-	return await invoke('ask', { message: message });
+// Saved conversations, the most recently updated first.
+export async function listConversations(): Promise<ConversationSummary[]> {
+	return await invoke('list_conversations');
 }
 
-export async function cancelInference(): Promise<boolean> {
-	return await invoke('cancel_inference');
+// Makes a saved conversation the current chat and returns its history.
+export async function openConversation(conversationId: number): Promise<Message[]> {
+	return await invoke('open_conversation', { conversationId: conversationId });
+}
+
+// Messages of the saved conversations matching the words of the query, the
+// most relevant first.
+export async function searchConversations(
+	query: string,
+	limit: number | null = null
+): Promise<SearchResult[]> {
+	return await invoke('search_conversations', { query: query, limit: limit });
+}
+
+export async function renameConversation(conversationId: number, title: string): Promise<void> {
+	await invoke('rename_conversation', { conversationId: conversationId, title: title });
+}
+
+// Deletes a saved conversation, starting a new chat if it was the current one.
+export async function deleteConversation(conversationId: number): Promise<void> {
+	await invoke('delete_conversation', { conversationId: conversationId });
+}
+
+// Asks for a folder and writes the conversation to it, resolving to the path
+// of the file.
+export async function exportConversation(
+	conversationId: number,
+	format: ExportFormat
+): Promise<string> {
+	return await invoke('export_conversation', { conversationId: conversationId, format: format });
+}
+
+// Asks for a folder and writes every conversation to it, one file each.
+export async function exportAllConversations(format: ExportFormat): Promise<string> {
+	return await invoke('export_all_conversations', { format: format });
+}
+
+// Asks for a ChatGPT `conversations.json` export or a JSON or JSONL list of
+// role/content messages and saves what it holds, resolving to the number of
+// conversations imported.
+export async function importConversations(): Promise<number> {
+	return await invoke('import_conversations');
+}
+
+// Switches to the branch through the message and returns its history.
+export async function selectBranch(messageId: number): Promise<Message[]> {
+	return await invoke('select_branch', { messageId: messageId });
+}
+
+// The request id arrives in `inference_submitted`, matched through a client
+// id. Tokens can arrive before that, so they are kept until the id is known
+// and then only the ones for this request are used.
+async function streamRequest(
+	command: string,
+	args: Record<string, unknown>,
+	onStart: (requestId: number) => void,
+	onToken: TokenCallback
+): Promise<Completion> {
+	const clientId = crypto.randomUUID();
+	let requestId: number | null = null;
+	let earlyTokens: NewTokenPayload[] = [];
+
+	const unlistenSubmitted = await listen<SubmittedPayload>('inference_submitted', (event) => {
+		if (requestId != null || event.payload.client_id != clientId) return;
+		requestId = event.payload.request_id;
+		onStart(requestId);
+		earlyTokens
+			.filter((payload) => payload.request_id == requestId)
+			.forEach((payload) => onToken(payload.message, payload.probabilities));
+		earlyTokens = [];
+	});
+	const unlistenToken = await listen<NewTokenPayload>('new_token', (event) => {
+		if (requestId == null) {
+			earlyTokens.push(event.payload);
+		} else if (event.payload.request_id == requestId) {
+			onToken(event.payload.message, event.payload.probabilities);
+		}
+	});
+
+	try {
+		return await invoke<Completion>(command, { ...args, clientId: clientId });
+	} finally {
+		unlistenSubmitted();
+		unlistenToken();
+	}
 }
@@ -2,6 +2,35 @@ export type Message = {
 	id: number;
 	text: string;
 	role: MessageRole;
+	// Alternatives to the message, itself included, oldest first. Only set on
+	// messages from the chat history.
+	siblings?: number[];
+};
+
+export type ConversationSummary = {
+	id: number;
+	title: string;
+	model_filename: string;
+	// Seconds since the Unix epoch
+	created_at: number;
+	updated_at: number;
+};
+
+export type ExportFormat = 'markdown' | 'json' | 'html';
+
+export type SnippetPart = {
+	text: string;
+	highlighted: boolean;
+};
+
+export type SearchResult = {
+	conversation_id: number;
+	conversation_title: string;
+	message_id: number;
+	role: MessageRole;
+	snippet: SnippetPart[];
+	// Seconds since the Unix epoch
+	created_at: number;
 };
 
 export enum MessageRole {
@@ -24,15 +53,86 @@ export type LanguageModelInfo = {
 	url: string;
 	image: string;
 	prompt_template: string;
+	chat_template?: ChatTemplate;
 	size: string;
 };
 
+export type ChatTemplate = {
+	system_prompt: string;
+	system: string;
+	user_turn: string;
+	assistant_turn: string;
+	generation_prefix: string;
+	bos: string;
+	eos: string;
+};
+
 export type CommandResponseLanguagesModels = {
 	models: LanguageModel[];
 };
 
+export interface TokenAlternative {
+	token: string;
+	logprob: number;
+}
+
+export interface TokenProbability extends TokenAlternative {
+	alternatives: TokenAlternative[];
+}
+
 export interface NewTokenPayload {
-	message: String;
+	request_id: number;
+	message: string;
+	probabilities?: TokenProbability[];
+}
+
+export interface CompletionStats {
+	prompt_tokens: number;
+	generated_tokens: number;
+	feed_prompt_ms: number;
+	predict_ms: number;
+	tokens_per_second: number;
+	finished_at: number;
+}
+
+export type StopReason = 'end_of_text' | 'max_tokens' | 'stop_sequence' | 'cancelled';
+
+export interface Completion {
+	request_id: number;
+	answer: string;
+	stop_reason: StopReason;
+	stats: CompletionStats;
+}
+
+export interface CommandError {
+	kind: 'no_model_loaded' | 'context_full' | 'halted' | 'inference_failed' | 'invalid_message';
+	message: string;
+}
+
+export interface SubmittedPayload {
+	request_id: number;
+	client_id: string | null;
+}
+
+export interface InferenceFinishedPayload {
+	request_id: number;
+	completion: Completion | null;
+	error: CommandError | null;
+}
+
+export interface QueuedPayload {
+	request_id: number;
+	position: number;
+}
+
+export interface HistoryTrimmedPayload {
+	request_id: number;
+	trimmed_turns: number;
+}
+
+export interface ConversationTitledPayload {
+	conversation_id: number;
+	title: string;
 }
 
 export interface TextBlock {
@@ -53,3 +153,63 @@ export interface DownloadProgress {
 	total: number;
 	percentage: number;
 }
+
+export type SamplerStrategy =
+	| 'top_p_top_k'
+	| 'mirostat'
+	| 'mirostat_v2'
+	| 'typical'
+	| 'tail_free'
+	| 'min_p';
+
+export type SamplerParameters = {
+	strategy: SamplerStrategy;
+	temperature: number;
+	top_p: number;
+	top_k: number;
+	repetition_penalty: number;
+	repetition_penalty_last_n: number;
+	mirostat_tau: number;
+	mirostat_eta: number;
+	typical_p: number;
+	tail_free_z: number;
+	min_p: number;
+};
+
+export type InferenceParameters = SamplerParameters & {
+	maximum_token_count: number;
+	context_size: number;
+	thread_count: number;
+	seed: number | null;
+};
+
+export type Preset = {
+	name: string;
+	parameters: SamplerParameters;
+	prompt_template?: string;
+	chat_template?: ChatTemplate;
+	built_in: boolean;
+};
+
+export type ParameterSource = 'model' | 'catalog' | 'global';
+
+// Values to save for the active model, the ones left out are kept
+export type ProfileUpdate = {
+	prompt_template?: string;
+	chat_template?: ChatTemplate;
+	parameters?: InferenceParameters;
+	// An empty list goes back to the default stop sequences
+	stop_sequences?: string[];
+};
+
+export type GetParametersResponse = {
+	prompt_template: string;
+	parameters: InferenceParameters;
+	stop_sequences: string[];
+	sources: {
+		prompt_template: ParameterSource;
+		chat_template: ParameterSource;
+		parameters: ParameterSource;
+		stop_sequences: ParameterSource;
+	};
+};